@@ -0,0 +1,544 @@
+//! HTTP API to manage the server while it runs. Every request must carry the admin token as
+//! `Authorization: Bearer <token>`, answers are JSON.
+//!
+//! - `GET /api/stats[?window=<seconds>][&top=<n>]`: cache and query statistics
+//! - `GET /api/queries[?limit=<n>]`: the last queries of the query log
+//! - `GET /api/lists`: the lists in use
+//! - `POST /api/lists/refresh`: reloads every list
+//! - `POST /api/lists/rules`, `DELETE /api/lists/rules` with `{"list": <path>, "rule": <rule>}`:
+//!   adds a rule to a list, or removes it, then reloads the lists
+//! - `GET /api/blocking`: the pauses in effect
+//! - `POST /api/blocking/pause` with `{"group": <name>, "seconds": <n>}`, both optional: pauses
+//!   blocking, for every client and until resumed by default
+//! - `POST /api/blocking/resume` with `{"group": <name>}`, optional: resumes blocking
+//! - `POST /api/cache/flush`: empties the cache
+//! - `GET /api/cache/<name>`: what the cache knows about a name
+//!
+//! `GET /metrics` exports metrics in the Prometheus text format instead, to be scraped with the
+//! token as `authorization` credentials.
+
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::net::TcpListener;
+
+use crate::config::BlockingConfig;
+use crate::globals::{ADMIN_BODY_LIMIT, STATS_TOP, STATS_WINDOWS};
+use crate::lists;
+use crate::querylog::describe;
+use crate::result::{Error, Result};
+use crate::server::Server;
+use crate::stats::Summary;
+
+/// What a request failed with, answered as `{"error": <message>}`
+type ApiError = (StatusCode, String);
+
+#[derive(Deserialize, Default)]
+struct RuleBody {
+    list: String,
+    rule: String,
+}
+
+#[derive(Deserialize, Default)]
+struct PauseBody {
+    group: Option<String>,
+    seconds: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+struct ResumeBody {
+    group: Option<String>,
+}
+
+pub struct Admin {
+    server: Arc<Server>,
+    token: String,
+    /// The lists in use, reloaded when they change
+    blocking: Arc<BlockingConfig>,
+}
+
+impl Admin {
+    pub fn new(server: Arc<Server>, token: String, blocking: BlockingConfig) -> Self {
+        Self {
+            server,
+            token,
+            blocking: Arc::new(blocking),
+        }
+    }
+
+    /// Accepts HTTP connections forever, each of them being served in its own task.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("An error occurred: {}", e);
+                    continue;
+                }
+            };
+
+            let admin = Arc::clone(&self);
+            tokio::spawn(async move {
+                let service = service_fn(|request| {
+                    let admin = Arc::clone(&admin);
+                    async move { Ok::<_, Infallible>(admin.handle(request).await) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    eprintln!("An error occurred: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, request: Request<Incoming>) -> Response<Full<Bytes>> {
+        let is_metrics = request.method() == Method::GET && request.uri().path() == "/metrics";
+        let result = if !self.is_authorized(&request) {
+            Err((StatusCode::UNAUTHORIZED, "Missing or invalid token".into()))
+        } else if is_metrics {
+            let mut response = Response::new(Full::new(Bytes::from(self.server.metrics())));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            return response;
+        } else {
+            self.route(request).await
+        };
+
+        let (status, body) = match result {
+            Ok(body) => (StatusCode::OK, body),
+            Err((status, message)) => (status, json!({ "error": message })),
+        };
+
+        let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+        *response.status_mut() = status;
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        response
+    }
+
+    /// Compares the token in constant time, so that it can't be guessed byte by byte
+    fn is_authorized(&self, request: &Request<Incoming>) -> bool {
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        token.len() == self.token.len()
+            && token
+                .bytes()
+                .zip(self.token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    async fn route(&self, request: Request<Incoming>) -> std::result::Result<Value, ApiError> {
+        let method = request.method().clone();
+        let path = request.uri().path().to_owned();
+        let query = request.uri().query().unwrap_or_default().to_owned();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        match (method, segments.as_slice()) {
+            (Method::GET, ["api", "stats"]) => {
+                let windows = match param(&query, "window")? {
+                    Some(secs) => vec![Duration::from_secs(secs)],
+                    None => STATS_WINDOWS.to_vec(),
+                };
+                let top = param(&query, "top")?.unwrap_or(STATS_TOP as u64) as usize;
+                let cache = self.server.cache_stats();
+                let windows: Vec<Value> = windows
+                    .into_iter()
+                    .map(|window| summary(&self.server.query_stats(window, top)))
+                    .collect();
+
+                Ok(json!({
+                    "cache": { "hits": cache.hits, "misses": cache.misses, "size": cache.size },
+                    "windows": windows,
+                }))
+            }
+            (Method::GET, ["api", "queries"]) => {
+                let limit = param(&query, "limit")?.unwrap_or(100) as usize;
+                Ok(Value::Array(self.server.recent_queries(limit)))
+            }
+            (Method::GET, ["api", "lists"]) => Ok(self.lists()),
+            (Method::POST, ["api", "lists", "refresh"]) => self.reload().await,
+            (Method::POST, ["api", "lists", "rules"]) => {
+                let body: RuleBody = read_json(request).await?;
+                lists::add_rule(&self.blocking, &body.list, &body.rule).map_err(bad_request)?;
+                self.reload().await
+            }
+            (Method::DELETE, ["api", "lists", "rules"]) => {
+                let body: RuleBody = read_json(request).await?;
+                if !lists::remove_rule(&self.blocking, &body.list, &body.rule)
+                    .map_err(bad_request)?
+                {
+                    return Err((StatusCode::NOT_FOUND, format!("No rule {}", body.rule)));
+                }
+                self.reload().await
+            }
+            (Method::GET, ["api", "blocking"]) => Ok(self.pauses()),
+            (Method::POST, ["api", "blocking", "pause"]) => {
+                let body: PauseBody = read_json(request).await?;
+                self.server
+                    .pause_blocking(body.group.as_deref(), body.seconds.map(Duration::from_secs))
+                    .map_err(bad_request)?;
+                Ok(self.pauses())
+            }
+            (Method::POST, ["api", "blocking", "resume"]) => {
+                let body: ResumeBody = read_json(request).await?;
+                self.server
+                    .resume_blocking(body.group.as_deref())
+                    .map_err(bad_request)?;
+                Ok(self.pauses())
+            }
+            (Method::POST, ["api", "cache", "flush"]) => {
+                self.server.flush_cache();
+                Ok(json!({}))
+            }
+            (Method::GET, ["api", "cache", name]) => {
+                let entries: Vec<Value> = self
+                    .server
+                    .inspect_cache(name)
+                    .into_iter()
+                    .map(|entry| {
+                        json!({
+                            "type": entry.qtype.map(|qtype| qtype.to_string()),
+                            "kind": entry.kind,
+                            "records": entry.records.iter().map(describe).collect::<Vec<_>>(),
+                        })
+                    })
+                    .collect();
+                Ok(Value::Array(entries))
+            }
+            _ => Err((StatusCode::NOT_FOUND, format!("No such endpoint: {}", path))),
+        }
+    }
+
+    /// Every list in use, along with the groups using it
+    fn lists(&self) -> Value {
+        let client_groups = self.server.groups();
+
+        let mut lists: Vec<(String, Value, Vec<&str>)> = Vec::new();
+        for (group, policy) in client_groups.policies() {
+            for list in policy.lists() {
+                if let Some((_, _, groups)) = lists.iter_mut().find(|(name, ..)| *name == list.name)
+                {
+                    groups.push(group);
+                    continue;
+                }
+
+                let value = json!({
+                    "name": list.name,
+                    "mode": list.mode.map(|mode| mode.to_string()),
+                    "denied": list.len(),
+                    "allowed": list.allowed_len(),
+                    "accepted": list.stats.accepted,
+                    "rejected": list.stats.rejected,
+                    "duplicated": list.stats.duplicated,
+                });
+                lists.push((list.name.clone(), value, vec![group]));
+            }
+        }
+
+        let lists: Vec<Value> = lists
+            .into_iter()
+            .map(|(_, mut value, groups)| {
+                value["groups"] = json!(groups);
+                value
+            })
+            .collect();
+        Value::Array(lists)
+    }
+
+    /// Loads every list again, which can take a while with large lists
+    async fn reload(&self) -> std::result::Result<Value, ApiError> {
+        let blocking = Arc::clone(&self.blocking);
+        let groups = tokio::task::spawn_blocking(move || lists::load_groups(&blocking))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        self.server.set_groups(groups);
+
+        Ok(self.lists())
+    }
+
+    fn pauses(&self) -> Value {
+        let pauses: Vec<Value> = self
+            .server
+            .pause_status()
+            .into_iter()
+            .map(|pause| {
+                json!({
+                    "group": pause.group,
+                    "remaining": pause.remaining.map(|remaining| remaining.as_secs()),
+                })
+            })
+            .collect();
+
+        json!({ "paused": pauses })
+    }
+}
+
+fn summary(summary: &Summary) -> Value {
+    json!({
+        "window": summary.window.as_secs(),
+        "queries": summary.queries,
+        "blocked": summary.blocked,
+        "blocked_percent": summary.blocked_percent(),
+        "top_domains": top(&summary.top_domains),
+        "top_blocked": top(&summary.top_blocked),
+        "top_clients": top(&summary.top_clients),
+        "types": distribution(&summary.types),
+        "rcodes": distribution(&summary.rcodes),
+    })
+}
+
+/// `[{"name": <key>, "count": <count>}, ...]`, in the same order
+fn top<K: fmt::Display>(counts: &[(K, u64)]) -> Value {
+    counts
+        .iter()
+        .map(|(key, count)| json!({ "name": key.to_string(), "count": count }))
+        .collect()
+}
+
+/// `{<key>: <count>, ...}`
+fn distribution<K: fmt::Display>(counts: &[(K, u64)]) -> Value {
+    let counts: Map<String, Value> = counts
+        .iter()
+        .map(|(key, count)| (key.to_string(), json!(count)))
+        .collect();
+    Value::Object(counts)
+}
+
+/// Reads a JSON body, an empty one standing for the default value
+async fn read_json<T: DeserializeOwned + Default>(
+    request: Request<Incoming>,
+) -> std::result::Result<T, ApiError> {
+    let body = Limited::new(request.into_body(), ADMIN_BODY_LIMIT)
+        .collect()
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+        .to_bytes();
+    if body.is_empty() {
+        return Ok(T::default());
+    }
+
+    serde_json::from_slice(&body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// Reads a numeric parameter of the query string
+fn param(query: &str, key: &str) -> std::result::Result<Option<u64>, ApiError> {
+    let value = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value);
+
+    match value {
+        Some(value) => value.parse().map(Some).map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid {}: {}", key, value),
+            )
+        }),
+        None => Ok(None),
+    }
+}
+
+fn bad_request(e: Error) -> ApiError {
+    (StatusCode::BAD_REQUEST, e.to_string().trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    use hyper::client::conn::http1 as client;
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::config::{Config, ListConfig};
+
+    const TOKEN: &str = "secret";
+
+    /// An admin API on a local socket, serving a server whose only list is empty
+    struct Fixture {
+        addr: SocketAddr,
+        list: PathBuf,
+    }
+
+    impl Fixture {
+        async fn new(test: &str) -> Self {
+            let list =
+                std::env::temp_dir().join(format!("barthez-{}-{}.txt", test, std::process::id()));
+            std::fs::write(&list, "").unwrap();
+
+            let mut config = Config::default();
+            config.blocking.blocklists = vec![ListConfig {
+                path: list.to_string_lossy().into_owned(),
+                mode: None,
+            }];
+            config.blocking.allowlists = Vec::new();
+            config.query_log.enabled = false;
+
+            let server = Arc::new(Server::new(&config).unwrap());
+            let admin = Arc::new(Admin::new(server, TOKEN.to_owned(), config.blocking));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(admin.serve(listener));
+
+            Self { addr, list }
+        }
+
+        fn list(&self) -> String {
+            self.list.to_string_lossy().into_owned()
+        }
+
+        /// Sends a request with the token, and returns the status and JSON body of the answer
+        async fn send(&self, method: Method, path: &str, body: Value) -> (StatusCode, Value) {
+            self.send_with(Some(TOKEN), method, path, body).await
+        }
+
+        async fn send_with(
+            &self,
+            token: Option<&str>,
+            method: Method,
+            path: &str,
+            body: Value,
+        ) -> (StatusCode, Value) {
+            let stream = TcpStream::connect(self.addr).await.unwrap();
+            let (mut sender, connection) = client::handshake(TokioIo::new(stream)).await.unwrap();
+            tokio::spawn(connection);
+
+            let mut request = Request::builder()
+                .method(method)
+                .uri(path)
+                .header("host", self.addr.to_string());
+            if let Some(token) = token {
+                request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let body = if body.is_null() {
+                Bytes::new()
+            } else {
+                Bytes::from(body.to_string())
+            };
+            let request = request.body(Full::new(body)).unwrap();
+
+            let response = sender.send_request(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.list);
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_token() {
+        let fixture = Fixture::new("admin-token").await;
+
+        for token in [None, Some("wrong"), Some("secret2")] {
+            let (status, body) = fixture
+                .send_with(token, Method::GET, "/api/blocking", Value::Null)
+                .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert!(body["error"].is_string());
+        }
+
+        let (status, _) = fixture
+            .send(Method::GET, "/api/blocking", Value::Null)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume() {
+        let fixture = Fixture::new("admin-pause").await;
+
+        let (status, body) = fixture
+            .send(
+                Method::POST,
+                "/api/blocking/pause",
+                json!({ "seconds": 60 }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let paused = &body["paused"][0];
+        assert!(paused["group"].is_null());
+        assert!(paused["remaining"].as_u64().unwrap() <= 60);
+
+        let (status, body) = fixture
+            .send(
+                Method::POST,
+                "/api/blocking/pause",
+                json!({ "group": "kids" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("kids"));
+
+        let (status, body) = fixture
+            .send(Method::POST, "/api/blocking/resume", Value::Null)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["paused"], json!([]));
+
+        // Too long to have a deadline, which lasts until resumed
+        let huge = json!({ "seconds": u64::MAX });
+        let (status, body) = fixture
+            .send(Method::POST, "/api/blocking/pause", huge)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["paused"][0]["remaining"].is_null());
+    }
+
+    #[tokio::test]
+    async fn add_then_remove_a_rule() {
+        let fixture = Fixture::new("admin-rules").await;
+        let rule = json!({ "list": fixture.list(), "rule": "ads.example.com" });
+
+        let (status, body) = fixture
+            .send(Method::POST, "/api/lists/rules", rule.clone())
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["denied"], 1);
+        assert!(std::fs::read_to_string(&fixture.list)
+            .unwrap()
+            .contains("ads.example.com"));
+
+        let (status, body) = fixture
+            .send(Method::DELETE, "/api/lists/rules", rule.clone())
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["denied"], 0);
+
+        let (status, _) = fixture.send(Method::DELETE, "/api/lists/rules", rule).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let other = json!({ "list": "/etc/passwd", "rule": "ads.example.com" });
+        let (status, _) = fixture.send(Method::POST, "/api/lists/rules", other).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
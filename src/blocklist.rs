@@ -0,0 +1,411 @@
+use std::collections::HashSet;
+use std::fmt::{self, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use crate::globals::{BLOCKED_MNAME, BLOCKED_RNAME, CLASS_IN};
+use crate::packet::Packet;
+use crate::parser::{parse_line, ListStats, Pattern, Rule};
+use crate::question::Question;
+use crate::record::{Record, RecordPreamble, RecordType};
+use crate::result::{Error, Result, ResultCode};
+
+/// How queries for blocked names are answered. Some clients misbehave with some of these, hence
+/// the choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockingMode {
+    /// The name doesn't exist
+    NxDomain,
+    /// The name exists but has no record of the requested type
+    NoData,
+    /// `A` queries get `0.0.0.0` and `AAAA` queries get `::`, others get no data
+    NullIp,
+    /// `A` and `AAAA` queries get the given addresses, others get no data
+    Sink(Ipv4Addr, Option<Ipv6Addr>),
+    /// The server refuses to answer
+    Refused,
+}
+
+impl BlockingMode {
+    /// Fills `packet` with the answer to a blocked `question`, synthesized records get `ttl`.
+    pub fn answer(&self, question: &Question, ttl: u32, packet: &mut Packet) {
+        let (ipv4, ipv6) = match *self {
+            BlockingMode::NxDomain => {
+                packet.header.response_code = ResultCode::NXDomain;
+                return add_soa(question, ttl, packet);
+            }
+            BlockingMode::Refused => {
+                packet.header.response_code = ResultCode::Refused;
+                return;
+            }
+            BlockingMode::NoData => return add_soa(question, ttl, packet),
+            BlockingMode::NullIp => (Ipv4Addr::UNSPECIFIED, Some(Ipv6Addr::UNSPECIFIED)),
+            BlockingMode::Sink(ipv4, ipv6) => (ipv4, ipv6),
+        };
+
+        let preamble = RecordPreamble::new(&question.name, question.question_type, CLASS_IN, ttl);
+        let record = match (question.question_type, ipv6) {
+            (RecordType::A, _) => Record::A {
+                preamble,
+                addr: ipv4,
+            },
+            (RecordType::AAAA, Some(addr)) => Record::AAAA { preamble, addr },
+            // Nothing to answer with, which is the same as no data
+            _ => return add_soa(question, ttl, packet),
+        };
+
+        packet.answers.push(record);
+        packet.header.answer_count += 1;
+    }
+}
+
+/// Adds the SOA of a zone made up for the blocked name to the authority section, so that
+/// clients cache the negative answer for `ttl`, see [RFC2308#3](https://www.rfc-editor.org/rfc/rfc2308#section-3).
+fn add_soa(question: &Question, ttl: u32, packet: &mut Packet) {
+    packet.authorities.push(Record::SOA {
+        preamble: RecordPreamble::new(&question.name, RecordType::SOA, CLASS_IN, ttl),
+        mname: BLOCKED_MNAME.to_owned(),
+        rname: BLOCKED_RNAME.to_owned(),
+        serial: 1,
+        refresh: ttl,
+        retry: ttl,
+        expire: ttl,
+        minimum: ttl,
+    });
+    packet.header.authority_count += 1;
+}
+
+impl fmt::Display for BlockingMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockingMode::NxDomain => write!(f, "NXDOMAIN"),
+            BlockingMode::NoData => write!(f, "NODATA"),
+            BlockingMode::NullIp => write!(f, "NULL"),
+            BlockingMode::Sink(ipv4, Some(ipv6)) => write!(f, "SINK({ipv4}, {ipv6})"),
+            BlockingMode::Sink(ipv4, None) => write!(f, "SINK({ipv4})"),
+            BlockingMode::Refused => write!(f, "REFUSED"),
+        }
+    }
+}
+
+impl FromStr for BlockingMode {
+    type Err = Error;
+
+    /// Parses `nxdomain`, `nodata`, `null`, `refused` or `sink:<ipv4>[,<ipv6>]`
+    fn from_str(s: &str) -> Result<Self> {
+        let mode = match s.to_lowercase().as_str() {
+            "nxdomain" => BlockingMode::NxDomain,
+            "nodata" => BlockingMode::NoData,
+            "null" => BlockingMode::NullIp,
+            "refused" => BlockingMode::Refused,
+            other => {
+                let invalid = || Error::InvalidBlockingMode(s.to_owned());
+
+                let addrs = other.strip_prefix("sink:").ok_or_else(invalid)?;
+                let (ipv4, ipv6) = match addrs.split_once(',') {
+                    Some((ipv4, ipv6)) => (ipv4, Some(ipv6)),
+                    None => (addrs, None),
+                };
+                let ipv4 = ipv4.trim().parse().map_err(|_| invalid())?;
+                let ipv6 = match ipv6 {
+                    Some(ipv6) => Some(ipv6.trim().parse().map_err(|_| invalid())?),
+                    None => None,
+                };
+
+                BlockingMode::Sink(ipv4, ipv6)
+            }
+        };
+
+        Ok(mode)
+    }
+}
+
+/// Set of blocking rules. A domain blocks itself along with all of its subdomains, while globs
+/// and regular expressions block the names they match.
+///
+/// Domains are kept in hash sets, and a name is checked by looking each of its parents up, so
+/// that the cost of a check depends on the number of labels of the name and not on the size of
+/// the list. Globs and regular expressions are checked one after the other.
+#[derive(Default)]
+pub struct Blocklist {
+    /// Where the list comes from, to tell which one blocked a name
+    pub name: String,
+    /// Overrides the blocking mode of the policy for the domains of this list
+    pub mode: Option<BlockingMode>,
+    /// What became of the entries of the list when it was loaded
+    pub stats: ListStats,
+    domains: HashSet<String>,
+    patterns: Vec<(String, Regex)>,
+    allowed: HashSet<String>,
+    allowed_patterns: Vec<(String, Regex)>,
+}
+
+impl Blocklist {
+    pub fn new(name: &str, mode: Option<BlockingMode>) -> Self {
+        Self {
+            name: name.to_owned(),
+            mode,
+            ..Default::default()
+        }
+    }
+
+    /// Adds every rule of the file at `path`, which can be a hosts file, a plain list of domains
+    /// or Adblock filters (see `parser`). Lines which cannot be understood are skipped.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<ListStats> {
+        self.read(path, false)
+    }
+
+    /// Same as `load`, but every rule of the file allows names instead of blocking them.
+    pub fn load_allowlist<P: AsRef<Path>>(&mut self, path: P) -> Result<ListStats> {
+        self.read(path, true)
+    }
+
+    fn read<P: AsRef<Path>>(&mut self, path: P, allowlist: bool) -> Result<ListStats> {
+        let fd = File::open(path).map_err(|_| Error::InvalidInputPath)?;
+        let mut reader = BufReader::new(fd);
+
+        let mut stats = ListStats::default();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let len = reader
+                .read_until(b'\n', &mut line)
+                .map_err(|_| Error::FailedReadingFile)?;
+            if len == 0 {
+                break;
+            }
+
+            // Don't give up on the whole list because of a few invalid bytes
+            for rule in parse_line(&String::from_utf8_lossy(&line)) {
+                let rule = match rule {
+                    Ok(Rule::Deny(pattern)) if allowlist => Rule::Allow(pattern),
+                    Ok(rule) => rule,
+                    Err(_) => {
+                        stats.rejected += 1;
+                        continue;
+                    }
+                };
+
+                if self.add_rule(rule) {
+                    stats.accepted += 1;
+                } else {
+                    stats.duplicated += 1;
+                }
+            }
+        }
+
+        self.stats.accepted += stats.accepted;
+        self.stats.rejected += stats.rejected;
+        self.stats.duplicated += stats.duplicated;
+
+        Ok(stats)
+    }
+
+    /// Adds a rule to the list, returns `false` if it already was part of it.
+    pub fn add_rule(&mut self, rule: Rule) -> bool {
+        let (domains, patterns, pattern) = match rule {
+            Rule::Deny(pattern) => (&mut self.domains, &mut self.patterns, pattern),
+            Rule::Allow(pattern) => (&mut self.allowed, &mut self.allowed_patterns, pattern),
+        };
+
+        match pattern {
+            Pattern::Domain(domain) => domains.insert(domain),
+            Pattern::Regex { source, regex } => {
+                if patterns.iter().any(|(s, _)| *s == source) {
+                    return false;
+                }
+                patterns.push((source, regex));
+                true
+            }
+        }
+    }
+
+    /// Number of blocking rules
+    pub fn len(&self) -> usize {
+        self.domains.len() + self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty() && self.patterns.is_empty()
+    }
+
+    /// Number of allow rules
+    pub fn allowed_len(&self) -> usize {
+        self.allowed.len() + self.allowed_patterns.len()
+    }
+
+    /// Finds the rule blocking `qname`: a domain being `qname` itself or one of its parents,
+    /// or a pattern matching it.
+    pub fn find(&self, qname: &str) -> Option<&str> {
+        find_rule(&self.domains, &self.patterns, qname)
+    }
+
+    /// Finds the rule allowing `qname`, the same way as `find`.
+    pub fn find_allowed(&self, qname: &str) -> Option<&str> {
+        find_rule(&self.allowed, &self.allowed_patterns, qname)
+    }
+}
+
+/// Looks `qname` and each of its parents up in `domains`, then tries each of the `patterns`.
+fn find_rule<'a>(
+    domains: &'a HashSet<String>,
+    patterns: &'a [(String, Regex)],
+    qname: &str,
+) -> Option<&'a str> {
+    let qname = qname.to_lowercase();
+    let qname = qname.trim_end_matches('.');
+
+    let mut name = qname;
+    loop {
+        if let Some(domain) = domains.get(name) {
+            return Some(domain);
+        }
+
+        // Move on to the parent domain
+        match name.split_once('.') {
+            Some((_, parent)) => name = parent,
+            None => break,
+        }
+    }
+
+    patterns
+        .iter()
+        .find(|(_, regex)| regex.is_match(qname))
+        .map(|(source, _)| source.as_str())
+}
+
+/// The rule which decided the fate of a name
+#[derive(Debug, Clone)]
+pub struct Match {
+    /// The rule as written in its list: a domain, a glob or a regular expression
+    pub rule: String,
+    /// The name of the list the rule is part of
+    pub list: String,
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} from {}", self.rule, self.list)
+    }
+}
+
+/// What the policy decided for a name, and why
+#[derive(Debug, Clone)]
+pub enum Decision {
+    /// No rule matched, the name is resolved
+    Unmatched,
+    /// An allow rule matched, the name is resolved
+    Allowed(Match),
+    /// A deny rule matched, the name is answered with the mode
+    Blocked(Match, BlockingMode),
+}
+
+/// Every blocklist in use, along with how blocked names are answered.
+///
+/// Rules are evaluated against the lowercased name, in this order:
+///
+/// 1. allow rules of every list, domains then patterns: the first one matching allows the name
+/// 2. deny rules of each list in turn, domains then patterns: the first one matching blocks the
+///    name, with the blocking mode of its list if it has one
+///
+/// So an allow rule always beats a deny rule, whatever their lists.
+///
+/// Lists are shared, so that policies using the same ones don't each keep a copy.
+pub struct BlockingPolicy {
+    lists: Vec<Arc<Blocklist>>,
+    /// How blocked names are answered, unless their list says otherwise
+    pub mode: BlockingMode,
+    /// TTL of the records synthesized for blocked names
+    pub ttl: u32,
+}
+
+impl BlockingPolicy {
+    pub fn new(mode: BlockingMode, ttl: u32) -> Self {
+        Self {
+            lists: Vec::new(),
+            mode,
+            ttl,
+        }
+    }
+
+    pub fn add_list(&mut self, list: Arc<Blocklist>) {
+        self.lists.push(list);
+    }
+
+    pub fn lists(&self) -> &[Arc<Blocklist>] {
+        &self.lists
+    }
+
+    /// Total number of blocked domains, across all lists
+    pub fn len(&self) -> usize {
+        self.lists.iter().map(|list| list.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lists.iter().all(|list| list.is_empty())
+    }
+
+    /// Decides whether `qname` is blocked, see `BlockingPolicy` for the order of evaluation.
+    pub fn check(&self, qname: &str) -> Decision {
+        for list in &self.lists {
+            if let Some(rule) = list.find_allowed(qname) {
+                return Decision::Allowed(Match {
+                    rule: rule.to_owned(),
+                    list: list.name.clone(),
+                });
+            }
+        }
+
+        for list in &self.lists {
+            if let Some(rule) = list.find(qname) {
+                let matched = Match {
+                    rule: rule.to_owned(),
+                    list: list.name.clone(),
+                };
+                return Decision::Blocked(matched, list.mode.unwrap_or(self.mode));
+            }
+        }
+
+        Decision::Unmatched
+    }
+
+    /// Checks every CNAME target of `answers`, as trackers like to hide behind first-party
+    /// names. The first target blocked blocks the whole answer.
+    pub fn check_cnames(&self, answers: &[Record]) -> Decision {
+        answers
+            .iter()
+            .filter_map(|record| match record {
+                Record::CNAME { host, .. } => Some(host),
+                _ => None,
+            })
+            .map(|host| self.check(host))
+            .find(|decision| matches!(decision, Decision::Blocked(..)))
+            .unwrap_or(Decision::Unmatched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_answers_carry_an_soa() {
+        let question = Question::new("ads.example.com", RecordType::A);
+        for mode in [BlockingMode::NxDomain, BlockingMode::NoData] {
+            let mut packet = Packet::default();
+            mode.answer(&question, 30, &mut packet);
+
+            assert_eq!(packet.header.authority_count, 1);
+            assert!(
+                matches!(&packet.authorities[..], [Record::SOA { preamble, minimum: 30, .. }]
+                if preamble.name == "ads.example.com" && preamble.ttl() == 30)
+            );
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Formatter};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::globals::CLASS_IN;
+use crate::packet::{in_zone, is_delegation, Packet};
+use crate::record::{Record, RecordType};
+use crate::result::ResultCode;
+
+/// A cache entry is identified by the name, type and class of the question it answers. Entries
+/// without a type are about the name itself, whatever the type asked for.
+type CacheKey = (String, Option<RecordType>, u16);
+
+/// What a cache entry tells about the question it answers, see
+/// [RFC2308#2](https://www.rfc-editor.org/rfc/rfc2308#section-2) for negative answers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    /// The records answering the question
+    Answer,
+    /// The name does not exist, whatever the type
+    NxDomain,
+    /// The name exists but has no record of the requested type
+    NoData,
+    /// Addresses of a name server given along with a delegation, only good to reach it and
+    /// never served to clients
+    Glue,
+}
+
+struct CacheEntry {
+    kind: EntryKind,
+    /// The records as they were received, with their original TTL. For negative answers, the
+    /// SOA record of the zone with the negative TTL.
+    records: Vec<Record>,
+    /// When the records were received, to compute their remaining TTL
+    inserted: Instant,
+    /// When the first of the records expires
+    expires: Instant,
+    /// Last time the entry was used, the least recently used entries are evicted first
+    tick: u64,
+}
+
+impl CacheEntry {
+    /// The records with the TTL they have left at `now`
+    fn records_at(&self, now: Instant) -> Vec<Record> {
+        let elapsed = now.duration_since(self.inserted).as_secs() as u32;
+        self.records
+            .iter()
+            .cloned()
+            .map(|mut record| {
+                let preamble = record.preamble_mut();
+                preamble.set_ttl(preamble.ttl().saturating_sub(elapsed));
+                record
+            })
+            .collect()
+    }
+}
+
+/// A cache entry, as shown to whoever inspects the cache
+pub struct CachedEntry {
+    /// The type of the question answered, `None` if the entry is about the name as a whole
+    pub qtype: Option<RecordType>,
+    /// `answer`, `nxdomain`, `nodata` or `glue`
+    pub kind: &'static str,
+    /// The records with the TTL they have left
+    pub records: Vec<Record>,
+}
+
+/// Counters describing how useful the cache is
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Cache {{ hits: {}, misses: {}, size: {} }}",
+            self.hits, self.misses, self.size
+        )
+    }
+}
+
+/// In-memory cache of the records received from upstream servers.
+///
+/// Records are kept until their TTL runs out, and served back with their TTL decremented by
+/// the time spent in the cache. When the cache is full, the least recently used entry is
+/// evicted to make room for the new one.
+pub struct Cache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Keys of `entries` ordered by their last use
+    lru: BTreeMap<u64, CacheKey>,
+    /// Maximum number of entries
+    capacity: usize,
+    /// Logical clock, incremented every time an entry is used
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            capacity,
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            size: self.entries.len(),
+        }
+    }
+
+    /// Gets the answer to `qname`/`qtype`, if still valid. Records are served with the TTL they
+    /// have left, and negative answers are synthesized with the SOA they were received with.
+    pub fn get(&mut self, qname: &str, qtype: RecordType) -> Option<Packet> {
+        let entry = self
+            .lookup(qname, Some(qtype))
+            // Glue is only there to reach name servers, it isn't an answer
+            .filter(|(kind, _)| *kind != EntryKind::Glue)
+            .or_else(|| self.lookup(qname, None));
+
+        let (kind, records) = match entry {
+            Some(entry) => {
+                self.hits += 1;
+                entry
+            }
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+
+        let mut packet: Packet = Default::default();
+        packet.header.is_response = true;
+        match kind {
+            EntryKind::Answer => {
+                for rec in records {
+                    packet.answers.push(rec);
+                    packet.header.answer_count += 1;
+                }
+            }
+            EntryKind::NxDomain | EntryKind::NoData => {
+                if kind == EntryKind::NxDomain {
+                    packet.header.response_code = ResultCode::NXDomain;
+                }
+                for rec in records {
+                    packet.authorities.push(rec);
+                    packet.header.authority_count += 1;
+                }
+            }
+            EntryKind::Glue => {}
+        }
+
+        Some(packet)
+    }
+
+    /// Stores the answer to `qname`/`qtype` found in `packet`, as well as the delegation it
+    /// might contain (NS records of the authority section and their glue records).
+    ///
+    /// `zone` is the zone of the server which sent the packet, it can only delegate zones below
+    /// its own, and only give the glue records of the name servers within them.
+    ///
+    /// Negative answers are only cached along with the SOA of the zone, as its TTL and minimum
+    /// field bound how long they can be kept.
+    pub fn insert_packet(&mut self, qname: &str, qtype: RecordType, zone: &str, packet: &Packet) {
+        match packet.header.response_code {
+            ResultCode::NoError => {}
+            // When following a CNAME chain, the name which doesn't exist is not `qname`
+            ResultCode::NXDomain if packet.answers.is_empty() => {
+                if let Some(soa) = negative_soa(packet) {
+                    self.insert(qname, None, EntryKind::NxDomain, vec![soa]);
+                }
+                return;
+            }
+            _ => return,
+        }
+
+        if !packet.answers.is_empty() {
+            self.insert(
+                qname,
+                Some(qtype),
+                EntryKind::Answer,
+                packet.answers.clone(),
+            );
+        } else if let Some(soa) = negative_soa(packet) {
+            self.insert(qname, Some(qtype), EntryKind::NoData, vec![soa]);
+            return;
+        }
+
+        // Group the NS records by the zone they are authoritative for
+        let mut zones: HashMap<&str, Vec<Record>> = HashMap::new();
+        for record in &packet.authorities {
+            if let Record::NS { preamble, .. } = record {
+                // Don't let a server speak for zones unrelated to the question, or above its own
+                if is_delegation(qname, zone, &preamble.name) {
+                    zones
+                        .entry(preamble.name.as_str())
+                        .or_default()
+                        .push(record.clone());
+                }
+            }
+        }
+
+        // Only keep the glue records of the name servers we just cached, when they are within
+        // the zone they serve
+        let mut glues: HashMap<&str, Vec<Record>> = HashMap::new();
+        for record in &packet.additionals {
+            if let Record::A { preamble, .. } = record {
+                let is_glue = zones.iter().any(|(zone, records)| {
+                    in_zone(&preamble.name, zone)
+                        && records.iter().any(|ns| match ns {
+                            Record::NS { host, .. } => host.eq_ignore_ascii_case(&preamble.name),
+                            _ => false,
+                        })
+                });
+                if is_glue {
+                    glues
+                        .entry(preamble.name.as_str())
+                        .or_default()
+                        .push(record.clone());
+                }
+            }
+        }
+
+        for (zone, records) in zones {
+            self.insert(zone, Some(RecordType::NS), EntryKind::Answer, records);
+        }
+        for (host, records) in glues {
+            // An actual answer is better than glue
+            let answered = self
+                .entries
+                .get(&(host.to_lowercase(), Some(RecordType::A), CLASS_IN))
+                .is_some_and(|entry| entry.kind == EntryKind::Answer);
+            if !answered {
+                self.insert(host, Some(RecordType::A), EntryKind::Glue, records);
+            }
+        }
+    }
+
+    /// Every entry about `qname` still valid, without marking them as used
+    pub fn inspect(&self, qname: &str) -> Vec<CachedEntry> {
+        let qname = qname.to_lowercase();
+        let now = Instant::now();
+
+        let mut entries: Vec<CachedEntry> = self
+            .entries
+            .iter()
+            .filter(|((name, _, _), entry)| *name == qname && entry.expires > now)
+            .map(|((_, qtype, _), entry)| CachedEntry {
+                qtype: *qtype,
+                kind: match entry.kind {
+                    EntryKind::Answer => "answer",
+                    EntryKind::NxDomain => "nxdomain",
+                    EntryKind::NoData => "nodata",
+                    EntryKind::Glue => "glue",
+                },
+                records: entry.records_at(now),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.qtype);
+
+        entries
+    }
+
+    /// Drops every entry, the counters are kept
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+    }
+
+    /// Finds the address of a name server for the closest zone enclosing `qname` we know of,
+    /// along with the zone, so that a lookup can start from there instead of the root.
+    pub fn closest_name_server(&mut self, qname: &str) -> Option<(String, Ipv4Addr)> {
+        let mut zone = qname;
+        loop {
+            let hosts: Vec<String> = self
+                .records(zone, RecordType::NS)
+                .into_iter()
+                .filter_map(|record| match record {
+                    Record::NS { host, .. } => Some(host),
+                    _ => None,
+                })
+                .collect();
+
+            for host in hosts {
+                let addr = match self.lookup(&host, Some(RecordType::A)) {
+                    Some((EntryKind::Answer | EntryKind::Glue, records)) => {
+                        records.into_iter().find_map(|record| match record {
+                            Record::A { addr, .. } => Some(addr),
+                            _ => None,
+                        })
+                    }
+                    _ => None,
+                };
+                if let Some(addr) = addr {
+                    return Some((zone.to_owned(), addr));
+                }
+            }
+
+            // Move on to the parent zone, the root being left to the caller
+            zone = zone.split_once('.')?.1;
+        }
+    }
+
+    fn insert(
+        &mut self,
+        qname: &str,
+        qtype: Option<RecordType>,
+        kind: EntryKind,
+        records: Vec<Record>,
+    ) {
+        // Records with a TTL of 0 must not be cached
+        let ttl = match records.iter().map(|record| record.preamble().ttl()).min() {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+
+        let key = (qname.to_lowercase(), qtype, CLASS_IN);
+        self.remove(&key);
+
+        // Make room for the new entry
+        while self.entries.len() >= self.capacity {
+            match self.lru.pop_first() {
+                Some((_, key)) => {
+                    self.entries.remove(&key);
+                }
+                None => return,
+            }
+        }
+
+        let now = Instant::now();
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                kind,
+                records,
+                inserted: now,
+                expires: now + Duration::from_secs(ttl.into()),
+                tick: self.tick,
+            },
+        );
+    }
+
+    /// The records of a positive answer to `qname`/`qtype`, without counting hits and misses
+    fn records(&mut self, qname: &str, qtype: RecordType) -> Vec<Record> {
+        match self.lookup(qname, Some(qtype)) {
+            Some((EntryKind::Answer, records)) => records,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Gets an entry with the TTL of its records decremented, dropping it if it expired
+    fn lookup(
+        &mut self,
+        qname: &str,
+        qtype: Option<RecordType>,
+    ) -> Option<(EntryKind, Vec<Record>)> {
+        let key = (qname.to_lowercase(), qtype, CLASS_IN);
+        let now = Instant::now();
+
+        let entry = self.entries.get_mut(&key)?;
+        if entry.expires <= now {
+            self.remove(&key);
+            return None;
+        }
+
+        // Mark the entry as the most recently used one
+        self.tick += 1;
+        self.lru.remove(&entry.tick);
+        self.lru.insert(self.tick, key);
+        entry.tick = self.tick;
+
+        // Serve the records with the TTL they have left
+        Some((entry.kind, entry.records_at(now)))
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+        }
+    }
+}
+
+/// Gets the SOA record of the authority section, with its TTL capped to the negative TTL of the
+/// zone, see [RFC2308#5](https://www.rfc-editor.org/rfc/rfc2308#section-5).
+fn negative_soa(packet: &Packet) -> Option<Record> {
+    packet.authorities.iter().find_map(|record| match record {
+        Record::SOA { minimum, .. } => {
+            let mut soa = record.clone();
+            let preamble = soa.preamble_mut();
+            preamble.set_ttl(preamble.ttl().min(*minimum));
+            Some(soa)
+        }
+        _ => None,
+    })
+}
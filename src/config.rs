@@ -0,0 +1,515 @@
+//! Configuration of the server, read from a TOML file given with `--config <path>`. Every key is
+//! optional, those left out keep the defaults of `globals`. See `barthez.toml` for all of them.
+
+use std::collections::HashSet;
+use std::env;
+use std::fmt::{self, Formatter};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{Error as _, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crate::blocklist::BlockingMode;
+use crate::doh::HttpMethod;
+use crate::dot::server_config;
+use crate::forward::{normalize, Protocol, ResolutionMode, Strategy, Upstream};
+use crate::globals::{
+    ADMIN_ADDR, ALLOWLISTS, BLOCKING_MODE, BLOCKING_TTL, BLOCKLISTS, CACHE_SIZE, LISTEN_ADDR,
+    MAX_CONCURRENT_QUERIES, QUERY_LOG_DIR, QUERY_LOG_PRIVACY, QUERY_LOG_RETENTION_DAYS,
+    ROOT_SERVERS,
+};
+use crate::groups::Client;
+use crate::querylog::ClientPrivacy;
+use crate::result::{Error, Result};
+
+/// Types read from the same strings as their `FromStr` implementation
+macro_rules! deserialize_from_str {
+    ($($ty:ty),*) => {
+        $(
+            impl<'de> Deserialize<'de> for $ty {
+                fn deserialize<D: Deserializer<'de>>(
+                    deserializer: D,
+                ) -> std::result::Result<Self, D::Error> {
+                    let s = String::deserialize(deserializer)?;
+                    s.parse()
+                        .map_err(|e: Error| D::Error::custom(e.to_string().trim_end()))
+                }
+            }
+        )*
+    };
+}
+
+deserialize_from_str!(
+    BlockingMode,
+    ClientPrivacy,
+    Client,
+    ResolutionMode,
+    Strategy,
+    Protocol,
+    HttpMethod
+);
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: ListenConfig,
+    pub resolver: ResolverConfig,
+    pub cache: CacheConfig,
+    pub blocking: BlockingConfig,
+    pub query_log: QueryLogConfig,
+    pub admin: AdminConfig,
+}
+
+/// Addresses queries are served on, for each protocol
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub udp: Vec<SocketAddr>,
+    pub tcp: Vec<SocketAddr>,
+    pub tls: Vec<SocketAddr>,
+    /// PEM files of the certificate chain and private key TLS clients are served with
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        let addr = LISTEN_ADDR.parse().unwrap();
+        Self {
+            udp: vec![addr],
+            tcp: vec![addr],
+            tls: Vec::new(),
+            cert: None,
+            key: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    pub mode: ResolutionMode,
+    /// Resolvers queries are forwarded to, in forward mode
+    pub upstreams: Vec<UpstreamConfig>,
+    /// The order upstreams are tried in
+    pub strategy: Strategy,
+    /// Domains whose names are forwarded to upstreams of their own, whatever the mode
+    pub conditional: Vec<ConditionalConfig>,
+    /// Address upstream lookups are sent from
+    pub bind: IpAddr,
+    /// Source port of upstream lookups, 0 to pick a random one for each of them
+    pub port: u16,
+    /// Servers recursion starts from, in turn
+    pub root_servers: Vec<Ipv4Addr>,
+    pub max_concurrent_queries: usize,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            mode: ResolutionMode::default(),
+            upstreams: Vec::new(),
+            strategy: Strategy::default(),
+            conditional: Vec::new(),
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 0,
+            root_servers: ROOT_SERVERS.to_vec(),
+            max_concurrent_queries: MAX_CONCURRENT_QUERIES,
+        }
+    }
+}
+
+/// Upstreams of their own for the names of a domain, reverse zones included
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConditionalConfig {
+    pub domain: String,
+    pub upstreams: Vec<UpstreamConfig>,
+    #[serde(default)]
+    pub strategy: Strategy,
+}
+
+/// A resolver queries are forwarded to, written `<address>[:<port>]`,
+/// `tls://<address>[:<port>][#<name>]`, `https://<address>[:<port>][<path>][#<name>]` or as a
+/// table with the same fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamConfig {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+    /// Name the certificate of a TLS upstream must be valid for, its address if none
+    pub name: Option<String>,
+    /// PEM file of the CA certificates to trust instead of the usual ones
+    pub ca: Option<PathBuf>,
+    /// Base64 of the SHA-256 of the public key (SPKI) the certificate must have
+    pub pin: Option<String>,
+    /// Path queries are sent to on an HTTPS upstream, `DOH_PATH` if none
+    pub path: Option<String>,
+    /// How queries are sent to an HTTPS upstream, `POST` if none
+    pub method: Option<HttpMethod>,
+}
+
+impl FromStr for UpstreamConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (protocol, rest) = match s.split_once("://") {
+            Some((protocol, rest)) => (protocol.parse()?, rest),
+            None => (Protocol::Udp, s),
+        };
+        let (addr, name) = match rest.split_once('#') {
+            Some((addr, name)) => (addr, Some(name.to_owned())),
+            None => (rest, None),
+        };
+        let (addr, path) = match addr.find('/') {
+            Some(i) if protocol == Protocol::Https => (&addr[..i], Some(addr[i..].to_owned())),
+            _ => (addr, None),
+        };
+
+        Ok(Self {
+            addr: upstream_addr(addr, protocol)?,
+            protocol,
+            name,
+            ca: None,
+            pin: None,
+            path,
+            method: None,
+        })
+    }
+}
+
+/// The table form of `UpstreamConfig`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamTable {
+    addr: String,
+    #[serde(default)]
+    protocol: Protocol,
+    name: Option<String>,
+    ca: Option<PathBuf>,
+    pin: Option<String>,
+    path: Option<String>,
+    method: Option<HttpMethod>,
+}
+
+impl<'de> Deserialize<'de> for UpstreamConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct UpstreamVisitor;
+
+        impl<'de> Visitor<'de> for UpstreamVisitor {
+            type Value = UpstreamConfig;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                f.write_str("an address or a table")
+            }
+
+            fn visit_str<E: serde::de::Error>(
+                self,
+                s: &str,
+            ) -> std::result::Result<Self::Value, E> {
+                s.parse()
+                    .map_err(|e: Error| E::custom(e.to_string().trim_end()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> std::result::Result<Self::Value, A::Error> {
+                let table = UpstreamTable::deserialize(MapAccessDeserializer::new(map))?;
+                let addr = upstream_addr(&table.addr, table.protocol)
+                    .map_err(|e| A::Error::custom(e.to_string().trim_end()))?;
+
+                Ok(UpstreamConfig {
+                    addr,
+                    protocol: table.protocol,
+                    name: table.name,
+                    ca: table.ca,
+                    pin: table.pin,
+                    path: table.path,
+                    method: table.method,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(UpstreamVisitor)
+    }
+}
+
+/// Parses the address of an upstream, with the usual port of `protocol` if none is given
+fn upstream_addr(s: &str, protocol: Protocol) -> Result<SocketAddr> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    // IPv6 addresses can be given in brackets even without a port
+    let ip: IpAddr = s
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| Error::InvalidUpstream(s.to_owned()))?;
+
+    Ok(SocketAddr::new(ip, protocol.default_port()))
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub size: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self { size: CACHE_SIZE }
+    }
+}
+
+/// The lists and groups of clients, reloaded as a whole when lists change
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockingConfig {
+    pub mode: BlockingMode,
+    pub ttl: u32,
+    pub blocklists: Vec<ListConfig>,
+    pub allowlists: Vec<String>,
+    pub groups: Vec<GroupConfig>,
+}
+
+impl Default for BlockingConfig {
+    fn default() -> Self {
+        Self {
+            mode: BLOCKING_MODE,
+            ttl: BLOCKING_TTL,
+            blocklists: BLOCKLISTS
+                .iter()
+                .map(|(path, mode)| ListConfig {
+                    path: path.to_string(),
+                    mode: *mode,
+                })
+                .collect(),
+            allowlists: ALLOWLISTS.iter().map(|path| path.to_string()).collect(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl BlockingConfig {
+    /// Tells whether `path` is one of the lists in use
+    pub fn is_list(&self, path: &str) -> bool {
+        self.blocklists.iter().any(|list| list.path == path)
+            || self.allowlists.iter().any(|list| list == path)
+            || self.groups.iter().any(|group| {
+                group.blocklists.iter().any(|list| list == path)
+                    || group.allowlists.iter().any(|list| list == path)
+            })
+    }
+}
+
+/// A blocklist, and how to answer for its names if not the default blocking mode
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ListConfig {
+    pub path: String,
+    pub mode: Option<BlockingMode>,
+}
+
+/// A group of clients with a policy of its own. Clients who aren't part of any group get the
+/// lists of `BlockingConfig`.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    pub name: String,
+    /// Addresses, networks or MAC addresses
+    #[serde(default)]
+    pub clients: Vec<Client>,
+    /// Paths of the blocklists, which keep the mode they have in `BlockingConfig` if listed there
+    #[serde(default)]
+    pub blocklists: Vec<String>,
+    #[serde(default)]
+    pub allowlists: Vec<String>,
+    pub mode: Option<BlockingMode>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryLogConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// How many days of query log are kept, on top of the current one
+    pub retention_days: u64,
+    pub privacy: ClientPrivacy,
+}
+
+impl Default for QueryLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: PathBuf::from(QUERY_LOG_DIR),
+            retention_days: QUERY_LOG_RETENTION_DAYS,
+            privacy: QUERY_LOG_PRIVACY,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub addr: SocketAddr,
+    /// The API is only served if there is a token to protect it with. `BARTHEZ_ADMIN_TOKEN`
+    /// takes precedence, so that it doesn't have to be written in the file.
+    pub token: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            addr: ADMIN_ADDR.parse().unwrap(),
+            token: String::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the file given with `--config`, or keeps the defaults if there is none
+    pub fn from_args() -> Result<Self> {
+        let mut args = env::args().skip(1);
+        let mut path = None;
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                let value = args.next();
+                path = Some(value.ok_or_else(|| Error::InvalidConfig("--config: no path".into()))?);
+            } else if let Some(value) = arg.strip_prefix("--config=") {
+                path = Some(value.to_owned());
+            } else {
+                return Err(Error::InvalidConfig(format!("unknown argument {}", arg)));
+            }
+        }
+
+        let mut config = match path {
+            Some(path) => Self::load(&path)?,
+            None => {
+                println!("No configuration file given with --config, using the defaults");
+                Self::default()
+            }
+        };
+        if let Ok(token) = env::var("BARTHEZ_ADMIN_TOKEN") {
+            config.admin.token = token;
+        }
+
+        Ok(config)
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::InvalidConfig(format!("{}: {}", path, e)))?;
+        // The errors of the parser point to the offending key and show its line
+        let config: Config = toml::from_str(&contents)
+            .map_err(|e| Error::InvalidConfig(format!("{}: {}", path, e)))?;
+        config
+            .validate()
+            .map_err(|e| Error::InvalidConfig(format!("{}: {}", path, e)))?;
+
+        Ok(config)
+    }
+
+    /// Checks what the types alone can't, returning the key at fault along with the reason
+    fn validate(&self) -> std::result::Result<(), String> {
+        let listen = &self.listen;
+        if listen.udp.is_empty() && listen.tcp.is_empty() && listen.tls.is_empty() {
+            return Err("listen: no address to serve queries on".into());
+        }
+        if !listen.tls.is_empty() {
+            match (&listen.cert, &listen.key) {
+                (Some(cert), Some(key)) => {
+                    server_config(cert, key)
+                        .map_err(|e| format!("listen.cert: {}", e.to_string().trim_end()))?;
+                }
+                _ => return Err("listen.tls: serving TLS needs a cert and a key".into()),
+            }
+        }
+        if self.resolver.mode == ResolutionMode::Forward && self.resolver.upstreams.is_empty() {
+            return Err("resolver.upstreams: forwarding needs at least one upstream".into());
+        }
+        check_upstreams("resolver.upstreams", &self.resolver.upstreams)?;
+        let mut domains = HashSet::new();
+        for (i, conditional) in self.resolver.conditional.iter().enumerate() {
+            let domain = normalize(&conditional.domain);
+            if domain.is_empty() {
+                return Err(format!(
+                    "resolver.conditional[{}].domain: use the forward mode for every name",
+                    i
+                ));
+            }
+            if !domains.insert(domain) {
+                return Err(format!(
+                    "resolver.conditional[{}].domain: {} already has its upstreams",
+                    i, conditional.domain
+                ));
+            }
+            if conditional.upstreams.is_empty() {
+                return Err(format!(
+                    "resolver.conditional[{}].upstreams: at least one upstream is needed",
+                    i
+                ));
+            }
+            check_upstreams(
+                &format!("resolver.conditional[{}].upstreams", i),
+                &conditional.upstreams,
+            )?;
+        }
+        if self.resolver.root_servers.is_empty() {
+            return Err("resolver.root_servers: at least one server is needed".into());
+        }
+        if self.resolver.max_concurrent_queries == 0 {
+            return Err("resolver.max_concurrent_queries: must be at least 1".into());
+        }
+        if self.cache.size == 0 {
+            return Err("cache.size: must be at least 1".into());
+        }
+
+        let mut names = HashSet::new();
+        for (i, group) in self.blocking.groups.iter().enumerate() {
+            if group.name.is_empty() || group.name == "default" {
+                return Err(format!(
+                    "blocking.groups[{}].name: {:?} can't be used as a group name",
+                    i, group.name
+                ));
+            }
+            if !names.insert(group.name.as_str()) {
+                return Err(format!(
+                    "blocking.groups[{}].name: {} is already used by another group",
+                    i, group.name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Makes sure the settings of the upstreams at `key` can be used, and only apply to their
+/// protocol
+fn check_upstreams(key: &str, upstreams: &[UpstreamConfig]) -> std::result::Result<(), String> {
+    for (i, upstream) in upstreams.iter().enumerate() {
+        let has_tls = upstream.name.is_some() || upstream.ca.is_some() || upstream.pin.is_some();
+        let has_http = upstream.path.is_some() || upstream.method.is_some();
+        if upstream.protocol == Protocol::Udp && has_tls {
+            return Err(format!(
+                "{}[{}]: name, ca and pin only apply to TLS and HTTPS upstreams",
+                key, i
+            ));
+        }
+        if upstream.protocol != Protocol::Https && has_http {
+            return Err(format!(
+                "{}[{}]: path and method only apply to HTTPS upstreams",
+                key, i
+            ));
+        }
+
+        Upstream::new(upstream)
+            .map_err(|e| format!("{}[{}]: {}", key, i, e.to_string().trim_end()))?;
+    }
+
+    Ok(())
+}
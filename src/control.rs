@@ -0,0 +1,63 @@
+//! Commands typed on the standard input, to manage the server while it runs:
+//!
+//! - `pause <group|all> [seconds]`: pauses blocking, for good if no duration is given
+//! - `resume <group|all>`: resumes blocking
+//! - `status`: lists the pauses in effect
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+
+use crate::server::Server;
+
+/// Runs the commands read from the standard input, until it is closed.
+pub async fn serve_stdin(server: Arc<Server>) {
+    let mut lines = BufReader::new(stdin()).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        let result = match words[..] {
+            [] => continue,
+            ["pause", group] => server.pause_blocking(target(group), None),
+            ["pause", group, seconds] => match seconds.parse() {
+                Ok(seconds) => {
+                    server.pause_blocking(target(group), Some(Duration::from_secs(seconds)))
+                }
+                Err(_) => {
+                    eprintln!("Invalid duration: {}", seconds);
+                    continue;
+                }
+            },
+            ["resume", group] => server.resume_blocking(target(group)),
+            ["status"] => {
+                let pauses = server.pause_status();
+                if pauses.is_empty() {
+                    println!("Blocking is enabled");
+                }
+                for pause in pauses {
+                    println!("Blocking {}", pause);
+                }
+                continue;
+            }
+            _ => {
+                eprintln!("Unknown command: {}", line.trim());
+                continue;
+            }
+        };
+
+        match result {
+            Ok(()) => println!("Done: {}", line.trim()),
+            Err(e) => eprint!("{}", e),
+        }
+    }
+}
+
+/// The group a command is about, `None` standing for every client
+fn target(group: &str) -> Option<&str> {
+    match group {
+        "all" => None,
+        group => Some(group),
+    }
+}
@@ -0,0 +1,211 @@
+//! DNS over HTTPS upstreams, see [RFC8484](https://www.rfc-editor.org/rfc/rfc8484).
+//!
+//! Queries to an upstream share a single HTTP/2 connection, opened on the first of them and kept
+//! for as long as the upstream keeps it, each query being a stream of its own. Answers other
+//! than a DNS message with the `200 OK` status fail the lookup, so that the next upstream is
+//! tried and the client gets `SERVFAIL` if none answers.
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::client::conn::http2::{self, SendRequest};
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Method, Request, StatusCode, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::pki_types::ServerName;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+
+use crate::dot::{client_config, server_name};
+use crate::globals::{MAX_PACKET_SIZE, TCP_TIMEOUT};
+use crate::result::{Error, Result};
+
+/// Media type of the queries and answers
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// How queries are sent to an upstream over HTTPS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpMethod {
+    /// In the body of the request
+    #[default]
+    Post,
+    /// In the `dns` parameter of the URL, encoded in base64url, which caches along the way can
+    /// answer
+    Get,
+}
+
+impl FromStr for HttpMethod {
+    type Err = Error;
+
+    /// Parses `post` or `get`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "post" => Ok(HttpMethod::Post),
+            "get" => Ok(HttpMethod::Get),
+            _ => Err(Error::InvalidUpstream(format!("unknown method {}", s))),
+        }
+    }
+}
+
+/// An upstream reached over HTTPS
+pub struct HttpsClient {
+    addr: SocketAddr,
+    /// What the certificate of the upstream must be valid for
+    name: ServerName<'static>,
+    /// `https://<name>:<port><path>`, where queries are sent
+    uri: Uri,
+    method: HttpMethod,
+    connector: TlsConnector,
+    /// The connection queries are sent over, if any was opened yet
+    sender: Mutex<Option<SendRequest<Full<Bytes>>>>,
+}
+
+impl HttpsClient {
+    /// Sends queries to `path` on the upstream at `addr`, whose certificate is checked the same
+    /// way as for TLS upstreams, see `TlsClient::new`.
+    pub fn new(
+        addr: SocketAddr,
+        name: Option<&str>,
+        ca: Option<&Path>,
+        pin: Option<&str>,
+        path: &str,
+        method: HttpMethod,
+    ) -> Result<Self> {
+        let authority = match name {
+            Some(name) => format!("{}:{}", name, addr.port()),
+            None => addr.to_string(),
+        };
+        let uri = format!("https://{}{}", authority, path)
+            .parse::<Uri>()
+            .ok()
+            .filter(|uri| uri.path() == path && uri.query().is_none())
+            .ok_or_else(|| Error::InvalidUpstream(format!("invalid path {}", path)))?;
+
+        let mut config = client_config(ca, pin)?;
+        config.alpn_protocols = vec![b"h2".to_vec()];
+
+        Ok(Self {
+            addr,
+            name: server_name(addr, name)?,
+            uri,
+            method,
+            connector: TlsConnector::from(Arc::new(config)),
+            sender: Mutex::new(None),
+        })
+    }
+
+    /// Sends a written query, and returns the raw answer.
+    pub async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let exchange = async {
+            let (sender, reused) = self.sender().await?;
+            match self.send(sender, query).await {
+                // The upstream may have closed the connection while it was idle, in which case
+                // a new one is opened
+                Err(Error::HttpRequestFailed) if reused => {
+                    self.send(self.sender().await?.0, query).await
+                }
+                result => result,
+            }
+        };
+
+        timeout(TCP_TIMEOUT, exchange)
+            .await
+            .map_err(|_| Error::LookupTimeout)?
+    }
+
+    async fn send(&self, mut sender: SendRequest<Full<Bytes>>, query: &[u8]) -> Result<Vec<u8>> {
+        let response = sender
+            .send_request(self.request(query)?)
+            .await
+            .map_err(|e| {
+                eprintln!("HTTPS request to {} failed: {}", self.addr, e);
+                Error::HttpRequestFailed
+            })?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Error::UnexpectedHttpResponse(format!(
+                "status {}",
+                response.status()
+            )));
+        }
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or_default();
+        if content_type != DNS_MESSAGE {
+            return Err(Error::UnexpectedHttpResponse(format!(
+                "content type {}",
+                content_type
+            )));
+        }
+
+        let body = Limited::new(response.into_body(), MAX_PACKET_SIZE)
+            .collect()
+            .await
+            .map_err(|_| Error::HttpRequestFailed)?;
+
+        Ok(body.to_bytes().to_vec())
+    }
+
+    fn request(&self, query: &[u8]) -> Result<Request<Full<Bytes>>> {
+        let request = Request::builder().header(ACCEPT, DNS_MESSAGE);
+        let request = match self.method {
+            HttpMethod::Post => request
+                .method(Method::POST)
+                .uri(self.uri.clone())
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .body(Full::new(Bytes::copy_from_slice(query))),
+            HttpMethod::Get => request
+                .method(Method::GET)
+                .uri(format!("{}?dns={}", self.uri, BASE64_URL.encode(query)))
+                .body(Full::default()),
+        };
+
+        request.map_err(|_| Error::InvalidUpstream(self.uri.to_string()))
+    }
+
+    /// The open connection, or a new one, along with whether it was already open
+    async fn sender(&self) -> Result<(SendRequest<Full<Bytes>>, bool)> {
+        let mut sender = self.sender.lock().await;
+        if let Some(sender) = sender.as_ref().filter(|s| !s.is_closed()) {
+            return Ok((sender.clone(), true));
+        }
+
+        let stream = TcpStream::connect(self.addr)
+            .await
+            .map_err(|_| Error::TCPConnectFailed)?;
+        let stream = self
+            .connector
+            .connect(self.name.clone(), stream)
+            .await
+            .map_err(|e| {
+                eprintln!("TLS handshake with {} failed: {}", self.addr, e);
+                Error::TlsHandshakeFailed
+            })?;
+
+        let (opened, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .map_err(|e| {
+                eprintln!("HTTP/2 handshake with {} failed: {}", self.addr, e);
+                Error::HttpRequestFailed
+            })?;
+        let addr = self.addr;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("HTTPS connection to {} failed: {}", addr, e);
+            }
+        });
+        *sender = Some(opened.clone());
+
+        Ok((opened, false))
+    }
+}
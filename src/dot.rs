@@ -0,0 +1,505 @@
+//! DNS over TLS, see [RFC7858](https://www.rfc-editor.org/rfc/rfc7858), to upstreams and for
+//! clients.
+//!
+//! Queries to an upstream share a single connection, opened on the first of them and kept for
+//! as long as the upstream keeps it. Several queries can be in flight at once, answers being
+//! matched to their query by ID.
+//!
+//! Clients are served the same way as over TCP once the handshake is done, see
+//! `Server::serve_tls`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex, MutexGuard};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::timeout;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use crate::globals::TCP_TIMEOUT;
+use crate::result::{Error, Result};
+use crate::server::{read_message, write_message};
+
+/// An upstream reached over TLS
+pub struct TlsClient {
+    addr: SocketAddr,
+    /// What the certificate of the upstream must be valid for
+    name: ServerName<'static>,
+    connector: TlsConnector,
+    /// The connection queries are sent over, if any was opened yet
+    connection: Mutex<Option<Arc<Connection>>>,
+}
+
+impl TlsClient {
+    /// Checks the certificate of the upstream at `addr` against `name`, or its address if none,
+    /// with the CA certificates of the PEM file `ca` or the usual ones. If given, `pin` is the
+    /// base64 of the SHA-256 of the public key (SPKI) the certificate must have.
+    pub fn new(
+        addr: SocketAddr,
+        name: Option<&str>,
+        ca: Option<&Path>,
+        pin: Option<&str>,
+    ) -> Result<Self> {
+        Ok(Self {
+            addr,
+            name: server_name(addr, name)?,
+            connector: TlsConnector::from(Arc::new(client_config(ca, pin)?)),
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Sends a written query, and returns the raw answer.
+    pub async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let exchange = async {
+            let (connection, reused) = self.connection().await?;
+            match connection.exchange(query).await {
+                // The upstream may have closed the connection while it was idle, in which case
+                // a new one is opened
+                Err(_) if reused => self.connection().await?.0.exchange(query).await,
+                result => result,
+            }
+        };
+
+        timeout(TCP_TIMEOUT, exchange)
+            .await
+            .map_err(|_| Error::LookupTimeout)?
+    }
+
+    /// The open connection, or a new one, along with whether it was already open
+    async fn connection(&self) -> Result<(Arc<Connection>, bool)> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref().filter(|c| !c.is_closed()) {
+            return Ok((Arc::clone(connection), true));
+        }
+
+        let stream = TcpStream::connect(self.addr)
+            .await
+            .map_err(|_| Error::TCPConnectFailed)?;
+        let stream = self
+            .connector
+            .connect(self.name.clone(), stream)
+            .await
+            .map_err(|e| {
+                eprintln!("TLS handshake with {} failed: {}", self.addr, e);
+                Error::TlsHandshakeFailed
+            })?;
+
+        let (reader, writer) = tokio::io::split(stream);
+        let opened = Arc::new(Connection {
+            writer: Mutex::new(writer),
+            pending: SyncMutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            next_id: AtomicU16::new(0),
+        });
+        tokio::spawn(Arc::clone(&opened).read(reader));
+        *connection = Some(Arc::clone(&opened));
+
+        Ok((opened, false))
+    }
+}
+
+/// A connection to an upstream, and the queries waiting for their answer on it
+struct Connection {
+    writer: Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    /// Queries in flight, by the ID they were sent with
+    pending: SyncMutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>,
+    closed: AtomicBool,
+    next_id: AtomicU16,
+}
+
+impl Connection {
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        // Queries get an ID of their own, the ones they come with may be the same
+        let (id, receiver) = {
+            let mut pending = self.pending();
+            if self.is_closed() {
+                return Err(Error::TCPSendFailed);
+            }
+
+            let mut id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            while pending.contains_key(&id) {
+                id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            }
+            let (sender, receiver) = oneshot::channel();
+            pending.insert(id, sender);
+            (id, receiver)
+        };
+        let _pending = Pending {
+            connection: self,
+            id,
+        };
+
+        let mut message = query.to_vec();
+        message[..2].copy_from_slice(&id.to_be_bytes());
+        if let Err(e) = write_message(&mut *self.writer.lock().await, &message).await {
+            self.close();
+            return Err(e);
+        }
+
+        let mut answer = receiver.await.map_err(|_| Error::TCPRecvFailed)?;
+        answer[..2].copy_from_slice(&query[..2]);
+
+        Ok(answer)
+    }
+
+    /// Hands each answer to the query waiting for it, until the connection is closed
+    async fn read(self: Arc<Self>, mut reader: ReadHalf<TlsStream<TcpStream>>) {
+        while let Ok(Some(message)) = read_message(&mut reader).await {
+            if message.len() < 2 {
+                continue;
+            }
+            let id = u16::from_be_bytes([message[0], message[1]]);
+            if let Some(sender) = self.pending().remove(&id) {
+                let _ = sender.send(message);
+            }
+        }
+
+        self.close();
+    }
+
+    /// Fails every query in flight, and keeps new ones from being sent
+    fn close(&self) {
+        let mut pending = self.pending();
+        self.closed.store(true, Ordering::Relaxed);
+        pending.clear();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// The queries in flight can't be left in an inconsistent state, it's fine to keep using
+    /// them if a task panicked while holding them.
+    fn pending(&self) -> MutexGuard<'_, HashMap<u16, oneshot::Sender<Vec<u8>>>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Forgets a query in flight once it's done, answered or not
+struct Pending<'a> {
+    connection: &'a Connection,
+    id: u16,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.connection.pending().remove(&self.id);
+    }
+}
+
+/// What the certificate of the upstream at `addr` must be valid for, `name` or its address
+pub fn server_name(addr: SocketAddr, name: Option<&str>) -> Result<ServerName<'static>> {
+    match name {
+        Some(name) => ServerName::try_from(name.to_owned())
+            .map_err(|_| Error::InvalidTlsConfig(format!("invalid name {}", name))),
+        None => Ok(ServerName::from(addr.ip())),
+    }
+}
+
+/// How certificates are checked, with the CA certificates of the PEM file `ca` or the usual
+/// ones, and against `pin` if given
+pub fn client_config(ca: Option<&Path>, pin: Option<&str>) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            let invalid = || Error::InvalidTlsConfig(format!("can't read {}", path.display()));
+            for cert in CertificateDer::pem_file_iter(path).map_err(|_| invalid())? {
+                roots
+                    .add(cert.map_err(|_| invalid())?)
+                    .map_err(|e| Error::InvalidTlsConfig(e.to_string()))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let provider = Arc::new(default_provider());
+    let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| Error::InvalidTlsConfig(e.to_string()))?;
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::InvalidTlsConfig(e.to_string()))?;
+
+    let config = match pin {
+        Some(pin) => {
+            let pin = BASE64
+                .decode(pin)
+                .ok()
+                .filter(|pin| pin.len() == SHA256.output_len())
+                .ok_or_else(|| Error::InvalidTlsConfig(format!("invalid pin {}", pin)))?;
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier { verifier, pin }))
+                .with_no_client_auth()
+        }
+        None => builder.with_webpki_verifier(verifier).with_no_client_auth(),
+    };
+
+    Ok(config)
+}
+
+/// Verifies certificates as usual, then makes sure they have the pinned public key, see
+/// [RFC7858#4.2](https://www.rfc-editor.org/rfc/rfc7858#section-4.2).
+#[derive(Debug)]
+struct PinnedVerifier {
+    verifier: Arc<WebPkiServerVerifier>,
+    /// SHA-256 of the SPKI
+    pin: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let verified = self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let cert = webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|e| rustls::Error::General(e.to_string()))?;
+        let spki = cert.subject_public_key_info();
+        if digest(&SHA256, spki.as_ref()).as_ref() != self.pin.as_slice() {
+            return Err(rustls::Error::General(
+                "the public key of the certificate isn't the pinned one".into(),
+            ));
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+/// How clients are served, with the certificate chain and private key of the PEM files `cert`
+/// and `key`
+pub fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig> {
+    let invalid = |path: &Path| Error::InvalidTlsConfig(format!("can't read {}", path.display()));
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .ok()
+        .filter(|certs| !certs.is_empty())
+        .ok_or_else(|| invalid(cert))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|_| invalid(key))?;
+
+    ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::InvalidTlsConfig(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::InvalidTlsConfig(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::packet::{Packet, PacketBuffer};
+    use crate::record::RecordType;
+
+    /// Queries for this name are answered after the ones sent after them
+    const SLOW: &str = "slow.test";
+
+    /// A certificate for `dns.test`, issued by a CA of its own
+    struct Fixture {
+        dir: PathBuf,
+        /// The base64 of the SHA-256 of the SPKI of the certificate
+        pin: String,
+    }
+
+    impl Fixture {
+        /// Writes `ca.pem`, `cert.pem` and `key.pem` to a directory named after `test`
+        fn new(test: &str) -> Self {
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec!["dns.test".to_owned()])
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+
+            let dir = std::env::temp_dir().join(format!("barthez-{}-{}", test, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+            std::fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+            std::fs::write(dir.join("key.pem"), key.serialize_pem()).unwrap();
+
+            Self {
+                dir,
+                pin: BASE64.encode(digest(&SHA256, &key.public_key_der())),
+            }
+        }
+
+        fn ca(&self) -> PathBuf {
+            self.dir.join("ca.pem")
+        }
+
+        /// Starts a server answering every query with the query itself, flagged as a response
+        async fn serve(&self) -> SocketAddr {
+            let config = server_config(&self.dir.join("cert.pem"), &self.dir.join("key.pem"));
+            let acceptor = TlsAcceptor::from(Arc::new(config.unwrap()));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(answer(acceptor.clone(), stream));
+                }
+            });
+
+            addr
+        }
+    }
+
+    /// Answers the queries of a connection as they come, each in a task of its own
+    async fn answer(acceptor: TlsAcceptor, stream: TcpStream) {
+        let Ok(stream) = acceptor.accept(stream).await else {
+            return;
+        };
+        let (mut reader, writer) = tokio::io::split(stream);
+        let writer = Arc::new(Mutex::new(writer));
+        while let Ok(Some(mut message)) = read_message(&mut reader).await {
+            let writer = Arc::clone(&writer);
+            tokio::spawn(async move {
+                if parse(&message).questions[0].name == SLOW {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
+                message[2] |= 0x80;
+                let _ = write_message(&mut *writer.lock().await, &message).await;
+            });
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn query(id: u16, qname: &str) -> Vec<u8> {
+        let mut packet = Packet::default();
+        packet.header.id = id;
+        packet.add_question(qname, RecordType::A).unwrap();
+
+        let mut buffer = PacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let len = buffer.pos();
+        buffer.get_range(0, len).unwrap().to_vec()
+    }
+
+    fn parse(message: &[u8]) -> Packet {
+        Packet::try_from(PacketBuffer::from(message.to_vec())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn exchange_with_ca() {
+        let fixture = Fixture::new("dot-ca");
+        let addr = fixture.serve().await;
+        let client = TlsClient::new(addr, Some("dns.test"), Some(&fixture.ca()), None).unwrap();
+
+        let answer = parse(&client.exchange(&query(7, "example.com")).await.unwrap());
+        assert!(answer.header.is_response);
+        assert_eq!(answer.header.id, 7);
+        assert_eq!(answer.questions[0].name, "example.com");
+    }
+
+    #[tokio::test]
+    async fn wrong_name_is_rejected() {
+        let fixture = Fixture::new("dot-name");
+        let addr = fixture.serve().await;
+        let client = TlsClient::new(addr, Some("other.test"), Some(&fixture.ca()), None).unwrap();
+
+        let result = client.exchange(&query(7, "example.com")).await;
+        assert!(matches!(result, Err(Error::TlsHandshakeFailed)));
+    }
+
+    #[tokio::test]
+    async fn wrong_pin_is_rejected() {
+        let fixture = Fixture::new("dot-pin");
+        let addr = fixture.serve().await;
+        let ca = fixture.ca();
+
+        let client = TlsClient::new(addr, Some("dns.test"), Some(&ca), Some(&fixture.pin));
+        assert!(client
+            .unwrap()
+            .exchange(&query(7, "example.com"))
+            .await
+            .is_ok());
+
+        let wrong = BASE64.encode([0; 32]);
+        let client = TlsClient::new(addr, Some("dns.test"), Some(&ca), Some(&wrong)).unwrap();
+        let result = client.exchange(&query(7, "example.com")).await;
+        assert!(matches!(result, Err(Error::TlsHandshakeFailed)));
+    }
+
+    #[tokio::test]
+    async fn pipelined_answers_are_matched_by_id() {
+        let fixture = Fixture::new("dot-pipelining");
+        let addr = fixture.serve().await;
+        let client = TlsClient::new(addr, Some("dns.test"), Some(&fixture.ca()), None).unwrap();
+
+        // Both queries go over the same connection, the first one being answered last
+        let (slow, fast) = (query(1, SLOW), query(2, "fast.test"));
+        let client = &client;
+        let exchange = |query| async move { (client.exchange(query).await, Instant::now()) };
+        let ((slow, slow_at), (fast, fast_at)) = tokio::join!(exchange(&slow), exchange(&fast));
+        assert!(fast_at < slow_at);
+
+        let (slow, fast) = (parse(&slow.unwrap()), parse(&fast.unwrap()));
+        assert_eq!(slow.header.id, 1);
+        assert_eq!(slow.questions[0].name, SLOW);
+        assert_eq!(fast.header.id, 2);
+        assert_eq!(fast.questions[0].name, "fast.test");
+    }
+}
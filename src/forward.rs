@@ -0,0 +1,296 @@
+//! Forwarding of queries to upstream resolvers, for networks where recursion from the root
+//! isn't possible. Upstreams are tried in an order given by a strategy, and those failing
+//! repeatedly are sidelined for a while, so that queries don't keep waiting on them.
+//!
+//! Names of some domains can be forwarded to upstreams of their own, such as internal zones
+//! only known to the office DNS, whatever the resolution mode.
+
+use std::fmt::{self, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::config::UpstreamConfig;
+use crate::doh::HttpsClient;
+use crate::dot::TlsClient;
+use crate::globals::{DOH_PATH, UPSTREAM_MAX_FAILURES, UPSTREAM_SIDELINE};
+use crate::result::{Error, Result};
+
+/// How queries are resolved when they aren't in the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResolutionMode {
+    /// Iteratively, from the root servers
+    #[default]
+    Recursive,
+    /// By asking the upstream resolvers
+    Forward,
+}
+
+impl FromStr for ResolutionMode {
+    type Err = Error;
+
+    /// Parses `recursive` or `forward`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "recursive" => Ok(ResolutionMode::Recursive),
+            "forward" => Ok(ResolutionMode::Forward),
+            _ => Err(Error::InvalidResolutionMode(s.to_owned())),
+        }
+    }
+}
+
+/// The order upstreams are tried in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// Always the first one, the next ones only if it fails
+    #[default]
+    Failover,
+    /// Each of them in turn
+    RoundRobin,
+    /// The one which answered the quickest lately
+    Fastest,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    /// Parses `failover`, `round-robin` or `fastest`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "failover" => Ok(Strategy::Failover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "fastest" => Ok(Strategy::Fastest),
+            _ => Err(Error::InvalidStrategy(s.to_owned())),
+        }
+    }
+}
+
+/// How queries reach an upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// Over UDP, or TCP if the answer doesn't fit
+    #[default]
+    Udp,
+    /// Over TLS, see `dot`
+    Tls,
+    /// Over HTTPS, see `doh`
+    Https,
+}
+
+impl Protocol {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Protocol::Udp => 53,
+            Protocol::Tls => 853,
+            Protocol::Https => 443,
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = Error;
+
+    /// Parses `udp`, `tls` or `https`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "udp" => Ok(Protocol::Udp),
+            "tls" => Ok(Protocol::Tls),
+            "https" => Ok(Protocol::Https),
+            _ => Err(Error::InvalidUpstream(format!("unknown protocol {}", s))),
+        }
+    }
+}
+
+/// A resolver queries are forwarded to, and how to reach it
+pub struct Upstream {
+    pub addr: SocketAddr,
+    pub transport: UpstreamTransport,
+}
+
+pub enum UpstreamTransport {
+    Udp,
+    Tls(TlsClient),
+    Https(HttpsClient),
+}
+
+impl Upstream {
+    pub fn new(config: &UpstreamConfig) -> Result<Self> {
+        let transport = match config.protocol {
+            Protocol::Udp => UpstreamTransport::Udp,
+            Protocol::Tls => UpstreamTransport::Tls(TlsClient::new(
+                config.addr,
+                config.name.as_deref(),
+                config.ca.as_deref(),
+                config.pin.as_deref(),
+            )?),
+            Protocol::Https => UpstreamTransport::Https(HttpsClient::new(
+                config.addr,
+                config.name.as_deref(),
+                config.ca.as_deref(),
+                config.pin.as_deref(),
+                config.path.as_deref().unwrap_or(DOH_PATH),
+                config.method.unwrap_or_default(),
+            )?),
+        };
+
+        Ok(Self {
+            addr: config.addr,
+            transport,
+        })
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.transport {
+            UpstreamTransport::Udp => write!(f, "{}", self.addr),
+            UpstreamTransport::Tls(_) => write!(f, "tls://{}", self.addr),
+            UpstreamTransport::Https(_) => write!(f, "https://{}", self.addr),
+        }
+    }
+}
+
+/// How an upstream has been doing lately
+#[derive(Default)]
+struct Health {
+    /// Failures in a row
+    failures: u32,
+    /// Until when the upstream is only tried if no other one can be
+    sidelined_until: Option<Instant>,
+    /// Smoothed time it took to answer
+    rtt: Option<Duration>,
+}
+
+impl Health {
+    fn is_sidelined(&self, now: Instant) -> bool {
+        self.sidelined_until.is_some_and(|until| until > now)
+    }
+}
+
+pub struct Forwarder {
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    /// Health of each upstream, in the same order
+    health: Mutex<Vec<Health>>,
+    /// Where the next round-robin starts
+    next: AtomicUsize,
+}
+
+impl Forwarder {
+    pub fn new(upstreams: &[UpstreamConfig], strategy: Strategy) -> Result<Self> {
+        let health = upstreams.iter().map(|_| Health::default()).collect();
+        Ok(Self {
+            upstreams: upstreams.iter().map(Upstream::new).collect::<Result<_>>()?,
+            strategy,
+            health: Mutex::new(health),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// The upstreams to try, in order, along with their index. Sidelined upstreams come last,
+    /// those recovering the soonest first, so that queries are still forwarded if all of them
+    /// are failing.
+    pub fn order(&self) -> Vec<(usize, &Upstream)> {
+        let health = self.health();
+        let now = Instant::now();
+
+        let mut order: Vec<usize> = (0..self.upstreams.len()).collect();
+        order.sort_by_key(|i| {
+            let health = &health[*i];
+            health.sidelined_until.filter(|_| health.is_sidelined(now))
+        });
+
+        let healthy = order
+            .iter()
+            .take_while(|i| !health[**i].is_sidelined(now))
+            .count();
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                order[..healthy].rotate_left(next % healthy.max(1));
+            }
+            // Upstreams which haven't answered yet come first, so that they get measured
+            Strategy::Fastest => {
+                order[..healthy].sort_by_key(|i| health[*i].rtt.unwrap_or_default())
+            }
+        }
+
+        order.into_iter().map(|i| (i, &self.upstreams[i])).collect()
+    }
+
+    /// Records that the upstream at `index` answered in `rtt`
+    pub fn success(&self, index: usize, rtt: Duration) {
+        let mut health = self.health();
+        let health = &mut health[index];
+
+        health.failures = 0;
+        health.sidelined_until = None;
+        // Smooth the RTT the same way TCP does, see RFC6298
+        health.rtt = Some(match health.rtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    /// Records that the upstream at `index` didn't answer, sidelining it if it keeps failing
+    pub fn failure(&self, index: usize) {
+        let mut health = self.health();
+        let health = &mut health[index];
+
+        health.failures += 1;
+        if health.failures >= UPSTREAM_MAX_FAILURES {
+            if !health.is_sidelined(Instant::now()) {
+                println!(
+                    "Upstream {} failed {} times in a row, sidelining it for {}s",
+                    self.upstreams[index],
+                    health.failures,
+                    UPSTREAM_SIDELINE.as_secs()
+                );
+            }
+            health.sidelined_until = Some(Instant::now() + UPSTREAM_SIDELINE);
+        }
+    }
+
+    /// The health is only made of counters, it's fine to keep using it if a task panicked while
+    /// holding it.
+    fn health(&self) -> MutexGuard<'_, Vec<Health>> {
+        self.health.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Upstreams of their own for the names of some domains
+#[derive(Default)]
+pub struct ConditionalForwarders {
+    /// Domains, lowercased and without their trailing dot, and their upstreams
+    rules: Vec<(String, Forwarder)>,
+}
+
+impl ConditionalForwarders {
+    pub fn add(&mut self, domain: &str, forwarder: Forwarder) {
+        self.rules.push((normalize(domain), forwarder));
+    }
+
+    /// Where names of `qname`'s domain are forwarded, along with the domain, the most specific
+    /// one winning
+    pub fn forwarder_for(&self, qname: &str) -> Option<(&str, &Forwarder)> {
+        let qname = normalize(qname);
+        self.rules
+            .iter()
+            .filter(|(domain, _)| {
+                qname == *domain
+                    || qname
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('.'))
+            })
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(domain, forwarder)| (domain.as_str(), forwarder))
+    }
+}
+
+/// Lowercases `name` and removes its trailing dot, so that domains can be compared
+pub fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
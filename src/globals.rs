@@ -1 +1,84 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::blocklist::BlockingMode;
+use crate::querylog::ClientPrivacy;
+
 pub(crate) const MAX_JUMPS: usize = 5;
+
+/// Maximum size of a DNS message over plain UDP, see RFC1035#4.2.1
+pub(crate) const UDP_PACKET_SIZE: usize = 512;
+/// Maximum size of a DNS message, bounded by the 2 bytes length prefix used over TCP
+pub(crate) const MAX_PACKET_SIZE: usize = 65535;
+/// UDP payload size advertised through EDNS(0), as recommended by the DNS flag day 2020
+pub(crate) const EDNS_PACKET_SIZE: usize = 1232;
+/// How long to wait on an upstream answer over TCP
+pub(crate) const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long an idle client TCP or TLS connection is kept open, see RFC7766#6.2.3
+pub(crate) const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many queries of a single client connection can be resolved at once, the next ones are
+/// only read once one of them is answered
+pub(crate) const STREAM_MAX_PENDING: usize = 32;
+/// How long a client has to complete the TLS handshake
+pub(crate) const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait on an upstream answer over UDP
+pub(crate) const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+/// Address queries are served on, over UDP and TCP
+pub(crate) const LISTEN_ADDR: &str = "0.0.0.0:2053";
+/// Servers recursion starts from, *a.root-servers.net*
+pub(crate) const ROOT_SERVERS: &[Ipv4Addr] = &[Ipv4Addr::new(198, 41, 0, 4)];
+/// Path queries are sent to on HTTPS upstreams, the one suggested by RFC8484
+pub(crate) const DOH_PATH: &str = "/dns-query";
+/// How many times in a row an upstream resolver can fail before being sidelined
+pub(crate) const UPSTREAM_MAX_FAILURES: u32 = 3;
+/// How long a failing upstream resolver is only tried if no other one can be
+pub(crate) const UPSTREAM_SIDELINE: Duration = Duration::from_secs(30);
+/// How many queries can be resolved at the same time, the others wait for a free slot
+pub(crate) const MAX_CONCURRENT_QUERIES: usize = 256;
+/// Maximum number of entries in the cache
+pub(crate) const CACHE_SIZE: usize = 10_000;
+/// The Internet class, the only one we deal with
+pub(crate) const CLASS_IN: u16 = 1;
+/// Primary name server of the zones made up for blocked names, under the reserved `.invalid`
+pub(crate) const BLOCKED_MNAME: &str = "blocked.invalid";
+/// Mailbox of the zones made up for blocked names
+pub(crate) const BLOCKED_RNAME: &str = "hostmaster.blocked.invalid";
+/// How often the cache and query statistics are reported
+pub(crate) const STATS_INTERVAL: Duration = Duration::from_secs(60);
+/// Windows of time the query statistics are reported over
+pub(crate) const STATS_WINDOWS: &[Duration] =
+    &[Duration::from_secs(3600), Duration::from_secs(86_400)];
+/// Granularity of the query statistics, windows slide by this much
+pub(crate) const STATS_BUCKET: Duration = Duration::from_secs(60);
+/// How many names and clients the query statistics rank
+pub(crate) const STATS_TOP: usize = 10;
+/// Files listing the domains to block, one per line, and how to answer for them if not the
+/// default `BLOCKING_MODE`
+pub(crate) const BLOCKLISTS: &[(&str, Option<BlockingMode>)] = &[("data/blocklist.txt", None)];
+/// Files listing the domains never to block, whatever the blocklists say
+pub(crate) const ALLOWLISTS: &[&str] = &["data/allowlist.txt"];
+/// Where the kernel exposes the ARP table, used to find the MAC address of clients
+pub(crate) const ARP_TABLE: &str = "/proc/net/arp";
+/// How long the ARP table is trusted before being read again
+pub(crate) const NEIGHBOURS_REFRESH: Duration = Duration::from_secs(30);
+/// How queries for blocked names are answered
+pub(crate) const BLOCKING_MODE: BlockingMode = BlockingMode::NullIp;
+/// TTL of the records synthesized for blocked names, kept short so that unblocking is quick
+pub(crate) const BLOCKING_TTL: u32 = 2;
+/// Directory of the query log, where a file of JSON lines is written every day
+pub(crate) const QUERY_LOG_DIR: &str = "logs";
+/// How many days of query log are kept, on top of the current one
+pub(crate) const QUERY_LOG_RETENTION_DAYS: u64 = 7;
+/// How much of the clients is written to the query log
+pub(crate) const QUERY_LOG_PRIVACY: ClientPrivacy = ClientPrivacy::Show;
+/// How many entries can wait to be written to the query log before new ones are dropped
+pub(crate) const QUERY_LOG_QUEUE: usize = 4096;
+/// How many of the last queries are kept in memory, to be looked at without reading the log
+pub(crate) const RECENT_QUERIES: usize = 1000;
+/// Address the admin API listens on, it is only started if there is a token
+pub(crate) const ADMIN_ADDR: &str = "127.0.0.1:8053";
+/// Maximum size of the body of an admin API request
+pub(crate) const ADMIN_BODY_LIMIT: usize = 64 * 1024;
+/// How many upstream servers get their own latency series in the metrics, the next ones being
+/// counted together
+pub(crate) const MAX_UPSTREAM_SERIES: usize = 1000;
@@ -0,0 +1,287 @@
+//! Client groups, so that devices on the same network can be filtered differently. A client is
+//! identified by its address, the network it is part of, or its MAC address as found in the
+//! neighbour table of the host.
+
+use std::collections::HashMap;
+use std::fmt::{self, Formatter};
+use std::fs;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::blocklist::BlockingPolicy;
+use crate::globals::{ARP_TABLE, NEIGHBOURS_REFRESH};
+use crate::result::{Error, Result};
+
+/// Name of the policy used for clients who aren't part of any group
+const DEFAULT_GROUP: &str = "default";
+
+/// What identifies the clients of a group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Client {
+    /// A single address
+    Addr(IpAddr),
+    /// Every address of a network, given by its CIDR notation
+    Network(IpAddr, u8),
+    /// The device with this MAC address. Only IPv4 clients can be told apart this way, as the
+    /// kernel only exposes the ARP table under `/proc`.
+    Mac([u8; 6]),
+}
+
+impl Client {
+    /// Tells whether the client at `addr` is this one, `mac` being looked up if needed
+    fn matches(&self, addr: IpAddr, mac: &mut impl FnMut() -> Option<[u8; 6]>) -> bool {
+        match *self {
+            Client::Addr(client) => client == addr,
+            Client::Network(network, prefix) => in_network(addr, network, prefix),
+            Client::Mac(client) => mac() == Some(client),
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Client::Addr(addr) => write!(f, "{}", addr),
+            Client::Network(network, prefix) => write!(f, "{}/{}", network, prefix),
+            Client::Mac(mac) => {
+                let mac: Vec<String> = mac.iter().map(|b| format!("{:02x}", b)).collect();
+                write!(f, "{}", mac.join(":"))
+            }
+        }
+    }
+}
+
+impl FromStr for Client {
+    type Err = Error;
+
+    /// Parses `192.168.1.10`, `192.168.1.0/24`, `fd00::/8` or `aa:bb:cc:dd:ee:ff`
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidClient(s.to_owned());
+        let s = s.trim();
+
+        if let Ok(addr) = s.parse::<IpAddr>() {
+            return Ok(Client::Addr(addr.to_canonical()));
+        }
+
+        if let Some((network, prefix)) = s.split_once('/') {
+            let network: IpAddr = network.parse().map_err(|_| invalid())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+            // IPv4-mapped networks are matched as IPv4 ones, the prefix losing the 96 bits of
+            // `::ffff:`
+            let canonical = network.to_canonical();
+            let prefix = match (network, canonical) {
+                (IpAddr::V6(_), IpAddr::V4(_)) => prefix.checked_sub(96).ok_or_else(invalid)?,
+                _ => prefix,
+            };
+            let max = if canonical.is_ipv4() { 32 } else { 128 };
+            if prefix > max {
+                return Err(invalid());
+            }
+            return Ok(Client::Network(canonical, prefix));
+        }
+
+        parse_mac(s).map(Client::Mac).ok_or_else(invalid)
+    }
+}
+
+/// A set of clients sharing the same policy
+pub struct ClientGroup {
+    pub name: String,
+    clients: Vec<Client>,
+    pub policy: BlockingPolicy,
+}
+
+impl ClientGroup {
+    pub fn new(name: &str, policy: BlockingPolicy) -> Self {
+        Self {
+            name: name.to_owned(),
+            clients: Vec::new(),
+            policy,
+        }
+    }
+
+    pub fn add_client(&mut self, client: Client) {
+        self.clients.push(client);
+    }
+
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+}
+
+/// The policy of every group, and the one of the clients who aren't part of any.
+///
+/// Groups are tried in the order they were added, the first one the client is part of decides
+/// of its policy.
+pub struct ClientGroups {
+    groups: Vec<ClientGroup>,
+    default: BlockingPolicy,
+    neighbours: Mutex<Neighbours>,
+}
+
+impl ClientGroups {
+    pub fn new(default: BlockingPolicy) -> Self {
+        Self {
+            groups: Vec::new(),
+            default,
+            neighbours: Mutex::new(Neighbours::default()),
+        }
+    }
+
+    pub fn add_group(&mut self, group: ClientGroup) {
+        self.groups.push(group);
+    }
+
+    /// The name and policy of every group, starting with the default one
+    pub fn policies(&self) -> impl Iterator<Item = (&str, &BlockingPolicy)> {
+        let groups = self
+            .groups
+            .iter()
+            .map(|group| (group.name.as_str(), &group.policy));
+        std::iter::once((DEFAULT_GROUP, &self.default)).chain(groups)
+    }
+
+    /// Tells whether `name` is one of the groups, the clients who aren't part of any forming the
+    /// `default` one
+    pub fn contains(&self, name: &str) -> bool {
+        name == DEFAULT_GROUP || self.groups.iter().any(|group| group.name == name)
+    }
+
+    /// The name of the group of the client at `addr`, and its policy
+    pub fn policy_for(&self, addr: IpAddr) -> (&str, &BlockingPolicy) {
+        let addr = addr.to_canonical();
+
+        // Only read the neighbour table if a group needs it, and at most once
+        let mut mac = None;
+        let mut lookup_mac = || {
+            *mac.get_or_insert_with(|| {
+                self.neighbours
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .mac(addr)
+            })
+        };
+
+        self.groups
+            .iter()
+            .find(|group| {
+                group
+                    .clients
+                    .iter()
+                    .any(|client| client.matches(addr, &mut lookup_mac))
+            })
+            .map_or((DEFAULT_GROUP, &self.default), |group| {
+                (group.name.as_str(), &group.policy)
+            })
+    }
+}
+
+/// MAC addresses of the hosts on the local networks, read from the ARP table
+#[derive(Default)]
+struct Neighbours {
+    macs: HashMap<IpAddr, [u8; 6]>,
+    refreshed: Option<Instant>,
+}
+
+impl Neighbours {
+    /// The MAC address of `addr`, the table being read again if it is too old
+    fn mac(&mut self, addr: IpAddr) -> Option<[u8; 6]> {
+        let stale = self
+            .refreshed
+            .is_none_or(|refreshed| refreshed.elapsed() >= NEIGHBOURS_REFRESH);
+        if stale {
+            self.refresh();
+        }
+
+        self.macs.get(&addr).copied()
+    }
+
+    /// Reads `/proc/net/arp`, whose lines look like this, after a header:
+    ///
+    /// ```text
+    /// 192.168.1.10     0x1         0x2         aa:bb:cc:dd:ee:ff     *        eth0
+    /// ```
+    fn refresh(&mut self) {
+        self.refreshed = Some(Instant::now());
+        self.macs.clear();
+
+        let table = match fs::read_to_string(ARP_TABLE) {
+            Ok(table) => table,
+            // Not on Linux, or no network at all
+            Err(_) => return,
+        };
+
+        for line in table.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (addr, flags, mac) = match fields[..] {
+                [addr, _, flags, mac, ..] => (addr, flags, mac),
+                _ => continue,
+            };
+            // Incomplete entries have no MAC address yet
+            if flags == "0x0" {
+                continue;
+            }
+            if let (Ok(addr), Some(mac)) = (addr.parse(), parse_mac(mac)) {
+                self.macs.insert(addr, mac);
+            }
+        }
+    }
+}
+
+/// Parses a MAC address written as 6 hexadecimal bytes separated by `:` or `-`
+fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0; 6];
+    let mut bytes = s.split([':', '-']);
+    for byte in mac.iter_mut() {
+        let hex = bytes.next()?;
+        if hex.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(hex, 16).ok()?;
+    }
+
+    bytes.next().is_none().then_some(mac)
+}
+
+/// Tells whether `addr` is part of the network `network/prefix`
+fn in_network(addr: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (addr, network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_networks_are_matched_as_ipv4() {
+        let client: Client = "::ffff:192.168.1.0/120".parse().unwrap();
+        assert_eq!(client, Client::Network("192.168.1.0".parse().unwrap(), 24));
+
+        let mut mac = || None;
+        assert!(client.matches("192.168.1.42".parse().unwrap(), &mut mac));
+        assert!(!client.matches("192.168.2.42".parse().unwrap(), &mut mac));
+    }
+
+    #[test]
+    fn invalid_prefixes_are_rejected() {
+        for network in [
+            "::ffff:192.168.1.0/80",
+            "::ffff:192.168.1.0/129",
+            "10.0.0.0/33",
+        ] {
+            assert!(network.parse::<Client>().is_err(), "{}", network);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Loading of the lists and groups in use, and editing of the list files, so that lists can be
+//! changed and reloaded while the server runs.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::blocklist::{BlockingMode, BlockingPolicy, Blocklist};
+use crate::config::BlockingConfig;
+use crate::groups::{ClientGroup, ClientGroups};
+use crate::parser::parse_line;
+use crate::result::{Error, Result};
+
+/// Every list loaded so far, so that a list used by several policies is only loaded once
+#[derive(Default)]
+struct Lists {
+    blocklists: HashMap<String, Arc<Blocklist>>,
+    allowlists: HashMap<String, Arc<Blocklist>>,
+}
+
+impl Lists {
+    /// Builds a policy out of the given lists, loading those which weren't already
+    fn policy(
+        &mut self,
+        mode: BlockingMode,
+        ttl: u32,
+        blocklists: &[(&str, Option<BlockingMode>)],
+        allowlists: &[String],
+    ) -> BlockingPolicy {
+        let mut policy = BlockingPolicy::new(mode, ttl);
+        for (path, mode) in blocklists {
+            let blocklist = self.blocklists.entry(path.to_string()).or_insert_with(|| {
+                let mut blocklist = Blocklist::new(path, *mode);
+                match blocklist.load(path) {
+                    Ok(stats) => println!("Loaded {}: {}", path, stats),
+                    Err(e) => eprintln!("Failed loading blocklist {}: {}", path, e),
+                }
+                Arc::new(blocklist)
+            });
+            policy.add_list(Arc::clone(blocklist));
+        }
+        for path in allowlists {
+            let allowlist = self.allowlists.entry(path.clone()).or_insert_with(|| {
+                let mut allowlist = Blocklist::new(path, None);
+                match allowlist.load_allowlist(path) {
+                    Ok(stats) => println!("Loaded {}: {}", path, stats),
+                    Err(e) => eprintln!("Failed loading allowlist {}: {}", path, e),
+                }
+                Arc::new(allowlist)
+            });
+            policy.add_list(Arc::clone(allowlist));
+        }
+
+        policy
+    }
+
+    /// The blocking mode the default blocklists give to the list at `path`, if any
+    fn mode(&self, path: &str) -> Option<BlockingMode> {
+        self.blocklists.get(path).and_then(|list| list.mode)
+    }
+}
+
+/// Loads every list of `config`, and builds the policy of each group out of them. A missing list
+/// only means less blocking.
+pub fn load_groups(config: &BlockingConfig) -> ClientGroups {
+    let mut lists = Lists::default();
+    let blocklists: Vec<(&str, Option<BlockingMode>)> = config
+        .blocklists
+        .iter()
+        .map(|list| (list.path.as_str(), list.mode))
+        .collect();
+    let policy = lists.policy(config.mode, config.ttl, &blocklists, &config.allowlists);
+    if policy.is_empty() {
+        eprintln!("No domain to block, every query will be resolved");
+    } else {
+        println!("Blocking {} domains ({})", policy.len(), policy.mode);
+    }
+
+    // Groups share the lists they have in common with the default policy and each other
+    let mut groups = ClientGroups::new(policy);
+    for group_config in &config.groups {
+        let blocklists: Vec<(&str, Option<BlockingMode>)> = group_config
+            .blocklists
+            .iter()
+            .map(|path| (path.as_str(), lists.mode(path)))
+            .collect();
+        let policy = lists.policy(
+            group_config.mode.unwrap_or(config.mode),
+            config.ttl,
+            &blocklists,
+            &group_config.allowlists,
+        );
+
+        let mut group = ClientGroup::new(&group_config.name, policy);
+        for client in &group_config.clients {
+            group.add_client(*client);
+        }
+        println!(
+            "Group {}: {} clients, blocking {} domains ({})",
+            group.name,
+            group.clients().len(),
+            group.policy.len(),
+            group.policy.mode
+        );
+        groups.add_group(group);
+    }
+
+    groups
+}
+
+/// Appends `rule` to the list at `path`, once checked that it can be understood.
+pub fn add_rule(config: &BlockingConfig, path: &str, rule: &str) -> Result<()> {
+    let rule = rule.trim();
+    check_rule(config, path, rule)?;
+
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|_| Error::FailedWritingFile)?;
+    // Don't glue the rule to the last line of the file
+    let separator = if contents.is_empty() || contents.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    writeln!(file, "{}{}", separator, rule).map_err(|_| Error::FailedWritingFile)
+}
+
+/// Removes every line of the list at `path` which is `rule`, returns `false` if there was none.
+pub fn remove_rule(config: &BlockingConfig, path: &str, rule: &str) -> Result<bool> {
+    let rule = rule.trim();
+    check_rule(config, path, rule)?;
+
+    let contents = fs::read_to_string(path).map_err(|_| Error::FailedReadingFile)?;
+    let kept: Vec<&str> = contents
+        .lines()
+        .filter(|line| line.trim() != rule)
+        .collect();
+    if kept.len() == contents.lines().count() {
+        return Ok(false);
+    }
+
+    let mut contents = kept.join("\n");
+    contents.push('\n');
+    fs::write(path, contents).map_err(|_| Error::FailedWritingFile)?;
+
+    Ok(true)
+}
+
+/// Makes sure `path` is a list in use, the only files which can be edited, and `rule` a single
+/// valid rule
+fn check_rule(config: &BlockingConfig, path: &str, rule: &str) -> Result<()> {
+    if !config.is_list(path) {
+        return Err(Error::UnknownList(path.to_owned()));
+    }
+
+    let rules = parse_line(rule);
+    if rule.contains(['\n', '\r']) || rules.len() != 1 {
+        return Err(Error::InvalidListEntry(rule.to_owned()));
+    }
+    rules.into_iter().next().transpose()?;
+
+    Ok(())
+}
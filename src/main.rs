@@ -1,62 +1,139 @@
+mod admin;
+mod blocklist;
+mod cache;
+mod config;
+mod control;
+mod doh;
+mod dot;
+mod forward;
 mod globals;
+mod groups;
 mod header;
+mod lists;
+mod metrics;
 mod packet;
+mod parser;
+mod pause;
+mod querylog;
 mod question;
 mod record;
 mod result;
 mod server;
+mod stats;
 
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::globals::{STATS_INTERVAL, STATS_TOP, STATS_WINDOWS};
 use crate::header::Header;
-use crate::packet::{Packet, PacketBuffer};
+use crate::packet::PacketBuffer;
+use crate::querylog::QueryLog;
 use crate::question::Question;
 use crate::record::Record;
-use crate::record::RecordType;
 use crate::result::{Error, Result};
 use crate::server::Server;
 
-use std::fs::File;
-use std::io::Read;
-use std::net::UdpSocket;
+use std::process;
+use std::sync::Arc;
 
-fn main() -> Result<()> {
-    let mut fd = File::open("data/dns_question.bin").map_err(|_| Error::InvalidInputPath)?;
-    let mut buffer = PacketBuffer::new();
-    fd.read(&mut buffer.bytes)
-        .map_err(|_| Error::FailedReadingFile)?;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
 
-    let packet = Packet::try_from(buffer)?;
-    println!("{}", packet);
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Configuration errors span several lines, pointing to the offending key
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            process::exit(1);
+        }
+    };
 
-    println!("------------------------------------");
+    // Upstream lookups use a random source port each by default, so that they can run
+    // concurrently
+    let server = Arc::new(Server::new(&config)?);
 
-    let mut fd = File::open("data/dns_answer.bin").map_err(|_| Error::InvalidInputPath)?;
-    let mut buffer = PacketBuffer::new();
-    fd.read(&mut buffer.bytes)
-        .map_err(|_| Error::FailedReadingFile)?;
+    // Load the blocked domains, a missing list only means less blocking
+    server.set_groups(lists::load_groups(&config.blocking));
 
-    // println!("RAW: {:#?}", &buffer.bytes[0..50]);
-    let packet = Packet::try_from(buffer)?;
-    println!("{}", packet);
+    // Record every query handled, clients can be hashed or hidden
+    if config.query_log.enabled {
+        let query_log = QueryLog::start(
+            &config.query_log.dir,
+            config.query_log.retention_days,
+            server.anonymizer(),
+        )?;
+        server.set_query_log(query_log);
+    }
 
-    println!("------------------------------------");
+    // Each query is handled in its own task on the runtime's thread pool, as are the loops
+    // accepting them
+    let mut servers = JoinSet::new();
+    for addr in &config.listen.udp {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .map_err(|_| Error::UDPBindFailed)?;
+        println!("Running server [{:?}]", socket);
+        servers.spawn(Arc::clone(&server).serve_udp(socket));
+    }
 
-    let server = Server::new("0.0.0.0".to_string(), 43210);
-    let p = server.recursive_lookup("yahoo.com", RecordType::MX)?;
-    println!("{}", p);
+    // TCP is there for clients whose answers don't fit over UDP
+    for addr in &config.listen.tcp {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|_| Error::TCPBindFailed)?;
+        println!("Running server [{:?}]", listener);
+        servers.spawn(Arc::clone(&server).serve_tcp(listener));
+    }
 
-    println!("------------------------------------");
+    // TLS is there for clients which keep their queries private, such as Android's Private DNS
+    if let (Some(cert), Some(key)) = (&config.listen.cert, &config.listen.key) {
+        let acceptor = TlsAcceptor::from(Arc::new(dot::server_config(cert, key)?));
+        for addr in &config.listen.tls {
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|_| Error::TCPBindFailed)?;
+            println!("Running TLS server [{:?}]", listener);
+            servers.spawn(Arc::clone(&server).serve_tls(listener, acceptor.clone()));
+        }
+    }
 
-    // Bind an UDP socket on port 2053
-    let socket = UdpSocket::bind(("0.0.0.0", 2053)).map_err(|_| Error::UDPBindFailed)?;
+    // Periodically report how the cache is doing, and what the clients are up to
+    let stats_server = Arc::clone(&server);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STATS_INTERVAL);
+        loop {
+            interval.tick().await;
+            println!("{}", stats_server.cache_stats());
+            for window in STATS_WINDOWS {
+                print!("{}", stats_server.query_stats(*window, STATS_TOP));
+            }
+        }
+    });
+
+    // Manage the server over HTTP, only if there is a token to protect the API with
+    if config.admin.token.is_empty() {
+        println!("Admin API disabled, set admin.token or BARTHEZ_ADMIN_TOKEN to enable it");
+    } else {
+        let listener = TcpListener::bind(config.admin.addr)
+            .await
+            .map_err(|_| Error::TCPBindFailed)?;
+        println!("Running admin API [{:?}]", listener);
 
-    println!("Running server [{:?}]", socket);
+        let admin = Admin::new(Arc::clone(&server), config.admin.token, config.blocking);
+        tokio::spawn(Arc::new(admin).serve(listener));
+    }
 
-    // For now, queries are handled sequentially, so an infinite loop for servicing
-    // requests is initiated.
-    loop {
-        match server.handle_query(&socket) {
-            Ok(_) => {}
-            Err(e) => eprintln!("An error occurred: {}", e),
+    // Blocking can be paused and resumed from the terminal
+    tokio::spawn(control::serve_stdin(Arc::clone(&server)));
+
+    // Servers only stop if they can't accept queries anymore
+    while let Some(result) = servers.join_next().await {
+        if let Ok(Err(e)) = result {
+            return Err(e);
         }
     }
+
+    Ok(())
 }
@@ -0,0 +1,285 @@
+//! Metrics exported in the Prometheus text format, see
+//! [Exposition formats](https://prometheus.io/docs/instrumenting/exposition_formats/).
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::cache::CacheStats;
+use crate::globals::MAX_UPSTREAM_SERIES;
+use crate::record::RecordType;
+use crate::result::{Error, ResultCode};
+
+/// Upper bounds of the buckets of the upstream latency, in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+/// Upper bounds of the buckets of the recursion depth
+const DEPTH_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0];
+
+/// Distribution of observed values over fixed buckets
+#[derive(Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Number of values in each bucket, not cumulated
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Writes the series of the histogram, `labels` being the labels of every series but `le`
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+
+        let mut cumulated = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulated += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, cumulated
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+struct Inner {
+    queries: HashMap<(RecordType, ResultCode), u64>,
+    blocked: u64,
+    /// Latency of the lookups to each upstream server, `None` for those past
+    /// `MAX_UPSTREAM_SERIES`
+    upstream_latency: HashMap<Option<SocketAddr>, Histogram>,
+    upstream_errors: HashMap<Option<SocketAddr>, u64>,
+    recursion_depth: Histogram,
+    parse_errors: HashMap<String, u64>,
+}
+
+/// Counters and histograms describing the health of the resolver
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                queries: HashMap::new(),
+                blocked: 0,
+                upstream_latency: HashMap::new(),
+                upstream_errors: HashMap::new(),
+                recursion_depth: Histogram::new(DEPTH_BUCKETS),
+                parse_errors: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl Metrics {
+    /// Counts a query handled
+    pub fn query(&self, qtype: RecordType, rcode: ResultCode, blocked: bool) {
+        let mut inner = self.inner();
+        *inner.queries.entry((qtype, rcode)).or_default() += 1;
+        if blocked {
+            inner.blocked += 1;
+        }
+    }
+
+    /// Records how long a lookup to `server` took, or that it failed
+    pub fn upstream(&self, server: SocketAddr, elapsed: Duration, success: bool) {
+        let mut inner = self.inner();
+
+        // Recursion contacts a lot of servers, don't let their number grow without bounds
+        let known = inner.upstream_latency.contains_key(&Some(server))
+            || inner.upstream_errors.contains_key(&Some(server));
+        let series = inner
+            .upstream_latency
+            .len()
+            .max(inner.upstream_errors.len());
+        let server = if known || series < MAX_UPSTREAM_SERIES {
+            Some(server)
+        } else {
+            None
+        };
+
+        if success {
+            inner
+                .upstream_latency
+                .entry(server)
+                .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+                .observe(elapsed.as_secs_f64());
+        } else {
+            *inner.upstream_errors.entry(server).or_default() += 1;
+        }
+    }
+
+    /// Records how deep a resolution had to go, 1 if it didn't need to resolve a name server
+    pub fn recursion_depth(&self, depth: usize) {
+        self.inner().recursion_depth.observe(depth as f64);
+    }
+
+    /// Counts a message which couldn't be parsed
+    pub fn parse_error(&self, error: &Error) {
+        // The name of the variant, without its fields
+        let variant = format!("{:?}", error);
+        let variant = variant.split('(').next().unwrap_or_default().to_owned();
+        *self.inner().parse_errors.entry(variant).or_default() += 1;
+    }
+
+    /// Writes every metric in the Prometheus text format
+    pub fn render(&self, cache: CacheStats) -> String {
+        let inner = self.inner();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "barthez_queries_total",
+            "counter",
+            "Queries handled",
+        );
+        let mut queries: Vec<_> = inner.queries.iter().collect();
+        queries.sort();
+        for ((qtype, rcode), count) in queries {
+            let _ = writeln!(
+                out,
+                "barthez_queries_total{{type=\"{}\",rcode=\"{}\"}} {}",
+                qtype, rcode, count
+            );
+        }
+
+        header(
+            &mut out,
+            "barthez_blocked_queries_total",
+            "counter",
+            "Queries answered by the blocking policy",
+        );
+        let _ = writeln!(out, "barthez_blocked_queries_total {}", inner.blocked);
+
+        header(
+            &mut out,
+            "barthez_cache_hits_total",
+            "counter",
+            "Cache hits",
+        );
+        let _ = writeln!(out, "barthez_cache_hits_total {}", cache.hits);
+        header(
+            &mut out,
+            "barthez_cache_misses_total",
+            "counter",
+            "Cache misses",
+        );
+        let _ = writeln!(out, "barthez_cache_misses_total {}", cache.misses);
+        header(
+            &mut out,
+            "barthez_cache_entries",
+            "gauge",
+            "Entries in the cache",
+        );
+        let _ = writeln!(out, "barthez_cache_entries {}", cache.size);
+
+        header(
+            &mut out,
+            "barthez_upstream_latency_seconds",
+            "histogram",
+            "Latency of the successful lookups to each upstream server",
+        );
+        let mut latencies: Vec<_> = inner.upstream_latency.iter().collect();
+        latencies.sort_by_key(|(server, _)| **server);
+        for (server, histogram) in latencies {
+            let labels = format!("nameserver=\"{}\"", nameserver(server));
+            histogram.write(&mut out, "barthez_upstream_latency_seconds", &labels);
+        }
+
+        header(
+            &mut out,
+            "barthez_upstream_errors_total",
+            "counter",
+            "Failed lookups to each upstream server",
+        );
+        let mut errors: Vec<_> = inner.upstream_errors.iter().collect();
+        errors.sort();
+        for (server, count) in errors {
+            let _ = writeln!(
+                out,
+                "barthez_upstream_errors_total{{nameserver=\"{}\"}} {}",
+                nameserver(server),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "barthez_recursion_depth",
+            "histogram",
+            "Nesting of the resolutions needed to answer a query",
+        );
+        inner
+            .recursion_depth
+            .write(&mut out, "barthez_recursion_depth", "");
+
+        header(
+            &mut out,
+            "barthez_parse_errors_total",
+            "counter",
+            "Messages which couldn't be parsed, by error",
+        );
+        let mut parse_errors: Vec<_> = inner.parse_errors.iter().collect();
+        parse_errors.sort();
+        for (variant, count) in parse_errors {
+            let _ = writeln!(
+                out,
+                "barthez_parse_errors_total{{error=\"{}\"}} {}",
+                variant, count
+            );
+        }
+
+        out
+    }
+
+    /// The metrics are only counters, it's fine to keep using them if a task panicked while
+    /// holding them.
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn nameserver(server: &Option<SocketAddr>) -> String {
+    match server {
+        Some(server) => server.to_string(),
+        None => "other".to_owned(),
+    }
+}
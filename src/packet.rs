@@ -1,8 +1,9 @@
+use std::collections::HashMap;
 use std::fmt::{self, Formatter};
 use std::io::Write;
 use std::net::Ipv4Addr;
 
-use crate::globals::MAX_JUMPS;
+use crate::globals::{MAX_JUMPS, MAX_PACKET_SIZE, UDP_PACKET_SIZE};
 use crate::record::RecordType;
 use crate::Header;
 use crate::Question;
@@ -13,20 +14,32 @@ use crate::{Error, Result};
 // for `Question`, `Record` and `Header`.
 #[derive(Debug)]
 pub struct PacketBuffer {
-    /// Bytes array containing a RAW DNS packet
-    pub bytes: [u8; 512],
+    /// Bytes containing a RAW DNS packet, grows as it gets written up to `limit`
+    pub bytes: Vec<u8>,
     /// Current position in the bytes array
     pos: usize,
+    /// Maximum size of the packet, depends on the transport it is read from/written to
+    limit: usize,
     /// Shadowed actual bit position
     _bit_pos: usize,
+    /// Offsets of the names (and their suffixes) already written, used for compression
+    labels: HashMap<String, usize>,
 }
 
 impl PacketBuffer {
+    /// Creates an empty buffer capped to the size of a plain UDP packet
     pub fn new() -> Self {
+        Self::with_limit(UDP_PACKET_SIZE)
+    }
+
+    /// Creates an empty buffer which can hold up to `limit` bytes
+    pub fn with_limit(limit: usize) -> Self {
         Self {
-            bytes: [0; 512],
+            bytes: Vec::new(),
             pos: 0,
+            limit: limit.min(MAX_PACKET_SIZE),
             _bit_pos: 0,
+            labels: HashMap::new(),
         }
     }
 
@@ -34,13 +47,14 @@ impl PacketBuffer {
         self.pos
     }
 
+    fn exhausted(&self, context: String) -> Error {
+        Error::PacketBufferExhausted(self.limit, context)
+    }
+
     /// Instead of writting this code everywhere...
     fn check_pos(&self) -> Result<()> {
-        if self.pos >= 512 {
-            return Err(Error::PacketBufferOver512(format!(
-                "check_pos(): self.pos = {}",
-                self.pos
-            )));
+        if self.pos >= self.bytes.len() {
+            return Err(self.exhausted(format!("check_pos(): self.pos = {}", self.pos)));
         }
 
         Ok(())
@@ -48,8 +62,8 @@ impl PacketBuffer {
 
     /// Gets byte `n` without consuming it
     fn get(&self, n: usize) -> Result<u8> {
-        if n >= 512 {
-            return Err(Error::PacketBufferOver512(format!("get(): n = {}", n)));
+        if n >= self.bytes.len() {
+            return Err(self.exhausted(format!("get(): n = {}", n)));
         }
 
         Ok(self.bytes[n])
@@ -62,8 +76,8 @@ impl PacketBuffer {
 
     /// Changes the buffer position
     fn seek(&mut self, n: usize) -> Result<()> {
-        if n >= 512 {
-            return Err(Error::PacketBufferOver512(format!("seek(): n = {}", n)));
+        if n > self.bytes.len() {
+            return Err(self.exhausted(format!("seek(): n = {}", n)));
         }
 
         self.pos = n;
@@ -71,11 +85,8 @@ impl PacketBuffer {
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
-            return Err(Error::PacketBufferOver512(format!(
-                "get_range(): start = {}, len = {}",
-                start, len
-            )));
+        if start + len > self.bytes.len() {
+            return Err(self.exhausted(format!("get_range(): start = {}, len = {}", start, len)));
         }
 
         Ok(&self.bytes[start..start + len])
@@ -204,9 +215,17 @@ impl PacketBuffer {
 
     /*     WRITE    */
     pub fn write_u8(&mut self, value: u8) -> Result<()> {
-        self.check_pos()?;
+        if self.pos >= self.limit {
+            return Err(self.exhausted(format!("write_u8(): self.pos = {}", self.pos)));
+        }
 
-        self.bytes[self.pos] = value;
+        // Either overwrite what was there or grow the buffer
+        if self.pos < self.bytes.len() {
+            self.bytes[self.pos] = value;
+        } else {
+            self.bytes.resize(self.pos, 0);
+            self.bytes.push(value);
+        }
         self.pos += 1;
 
         Ok(())
@@ -228,12 +247,33 @@ impl PacketBuffer {
         Ok(())
     }
 
+    /// Writes `qname` using the compression scheme from
+    /// [RFC1035#4.1.4](https://www.rfc-editor.org/rfc/rfc1035#section-4.1.4).
+    ///
+    /// Every suffix written is remembered along with its offset, so that when a later name ends
+    /// with an already known suffix, a 2 bytes pointer to it is emitted instead of the labels.
     pub fn write_qname(&mut self, qname: &str) -> Result<()> {
-        // Write each part of the domain
-        for label in qname.split('.') {
+        let labels: Vec<&str> = qname.split('.').filter(|l| !l.is_empty()).collect();
+
+        for i in 0..labels.len() {
+            // Names are case insensitive, `read_qname` lowercases them too
+            let suffix = labels[i..].join(".").to_lowercase();
+
+            // This suffix was already written, point to it and we're done
+            if let Some(&offset) = self.labels.get(&suffix) {
+                self.write_u16(0xC000 | offset as u16)?;
+                return Ok(());
+            }
+
+            // Pointers are only 14 bits long, offsets past that cannot be referenced
+            if self.pos < 0x4000 {
+                self.labels.insert(suffix, self.pos);
+            }
+
             // Double check the label length isn't over 63
+            let label = labels[i];
             let len = label.len();
-            if len >= 63 {
+            if len > 63 {
                 return Err(Error::LabelLengthOver63);
             }
 
@@ -252,11 +292,8 @@ impl PacketBuffer {
 
     /* SET */
     fn set_u8(&mut self, pos: usize, value: u8) -> Result<()> {
-        if pos >= 512 {
-            return Err(Error::PacketBufferOver512(format!(
-                "Cannot set value at {}",
-                pos
-            )));
+        if pos >= self.bytes.len() {
+            return Err(self.exhausted(format!("Cannot set value at {}", pos)));
         }
 
         self.bytes[pos] = value;
@@ -265,7 +302,7 @@ impl PacketBuffer {
 
     pub fn set_u16(&mut self, pos: usize, value: u16) -> Result<()> {
         self.set_u8(pos, ((value >> 8) & 0x00FF) as u8)?;
-        self.set_u8(pos, (value & 0x00FF) as u8)?;
+        self.set_u8(pos + 1, (value & 0x00FF) as u8)?;
         Ok(())
     }
 
@@ -290,7 +327,7 @@ impl Write for PacketBuffer {
     fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, std::io::Error> {
         for b in buf {
             self.write_u8(*b)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
         }
 
         Ok(buf.len())
@@ -301,16 +338,30 @@ impl Write for PacketBuffer {
     }
 }
 
-impl From<[u8; 512]> for PacketBuffer {
-    fn from(bytes: [u8; 512]) -> Self {
+impl Default for PacketBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Vec<u8>> for PacketBuffer {
+    fn from(bytes: Vec<u8>) -> Self {
         Self {
+            limit: MAX_PACKET_SIZE,
             bytes,
             pos: 0,
             _bit_pos: 0,
+            labels: HashMap::new(),
         }
     }
 }
 
+impl From<&[u8]> for PacketBuffer {
+    fn from(bytes: &[u8]) -> Self {
+        Self::from(bytes.to_vec())
+    }
+}
+
 /*
 impl TryFrom<Packet> for PacketBuffer {
     type Error = Error;
@@ -362,6 +413,42 @@ impl Packet {
         Ok(())
     }
 
+    /// Appends an EDNS(0) `OPT` record to the additional section
+    pub fn add_opt(
+        &mut self,
+        udp_payload_size: u16,
+        extended_rcode: u8,
+        dnssec_ok: bool,
+    ) -> Result<()> {
+        self.additionals
+            .push(Record::new_opt(udp_payload_size, extended_rcode, dnssec_ok));
+        self.header.additional_count += 1;
+
+        Ok(())
+    }
+
+    /// Gets the `OPT` record from the additional section, if the sender supports EDNS(0)
+    pub fn get_opt(&self) -> Option<&Record> {
+        self.additionals
+            .iter()
+            .find(|record| matches!(record, Record::OPT { .. }))
+    }
+
+    /// Drops every record but the `OPT` one and sets the TC bit, for answers which don't fit
+    /// in the client's transport
+    pub fn truncate(&mut self) {
+        self.header.is_truncated = true;
+
+        self.answers.clear();
+        self.authorities.clear();
+        self.additionals
+            .retain(|record| matches!(record, Record::OPT { .. }));
+
+        self.header.answer_count = 0;
+        self.header.authority_count = 0;
+        self.header.additional_count = self.additionals.len() as u16;
+    }
+
     pub fn get_random_a(&self) -> Option<Ipv4Addr> {
         self.answers.iter().find_map(|record| match record {
             Record::A { addr, .. } => Some(*addr),
@@ -369,34 +456,48 @@ impl Packet {
         })
     }
 
-    fn match_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
-        self.authorities.iter().filter_map(|record| match record {
-            Record::NS { preamble, host } => {
-                if qname.ends_with(&preamble.name) {
+    /// The NS records delegating `qname` to a zone below `zone`, the one of the server which
+    /// sent the packet, along with the zone they are for
+    fn match_ns<'a>(
+        &'a self,
+        qname: &'a str,
+        zone: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.authorities
+            .iter()
+            .filter_map(move |record| match record {
+                Record::NS { preamble, host } if is_delegation(qname, zone, &preamble.name) => {
                     Some((preamble.name.as_str(), host.as_str()))
-                } else {
-                    None
                 }
-            }
-            _ => None,
-        })
+                _ => None,
+            })
     }
 
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
-        self.match_ns(qname)
-            .flat_map(|(_, host)| {
+    pub fn get_resolved_ns<'a>(
+        &'a self,
+        qname: &'a str,
+        zone: &'a str,
+    ) -> Option<(&'a str, Ipv4Addr)> {
+        self.match_ns(qname, zone)
+            .flat_map(|(child, host)| {
                 self.additionals
                     .iter()
                     .filter_map(move |record| match record {
-                        Record::A { preamble, addr, .. } if preamble.name == host => Some(*addr),
+                        Record::A { preamble, addr, .. } if preamble.name == host => {
+                            Some((child, *addr))
+                        }
                         _ => None,
                     })
             })
             .next()
     }
 
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&str> {
-        self.match_ns(qname).map(|(_, host)| host).next()
+    pub fn get_unresolved_ns<'a>(
+        &'a self,
+        qname: &'a str,
+        zone: &'a str,
+    ) -> Option<(&'a str, &'a str)> {
+        self.match_ns(qname, zone).next()
     }
 
     pub fn write(&self, buffer: &mut PacketBuffer) -> Result<()> {
@@ -487,12 +588,14 @@ impl fmt::Display for Packet {
         }
 
         if !self.authorities.is_empty() {
+            writeln!(f, "Authorities [")?;
             for (i, authority) in self.authorities.iter().enumerate() {
                 writeln!(f, "{}", authority)?;
-                if i < self.answers.len() - 1 {
+                if i < self.authorities.len() - 1 {
                     writeln!(f, ",")?;
                 }
             }
+            writeln!(f, "]\n")?;
         }
 
         if !self.additionals.is_empty() {
@@ -509,3 +612,20 @@ impl fmt::Display for Packet {
         Ok(())
     }
 }
+
+/// Tells whether `name` is `zone` itself or one of its subdomains
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    let (name, zone) = (name.as_bytes(), zone.as_bytes());
+
+    zone.is_empty()
+        || name.eq_ignore_ascii_case(zone)
+        || (name.len() > zone.len()
+            && name[name.len() - zone.len()..].eq_ignore_ascii_case(zone)
+            && name[name.len() - zone.len() - 1] == b'.')
+}
+
+/// Tells whether a server for `zone` can delegate `qname` to `child`. A server only speaks for
+/// the zones below its own, those enclosing `qname` being the only ones of interest.
+pub fn is_delegation(qname: &str, zone: &str, child: &str) -> bool {
+    in_zone(qname, child) && in_zone(child, zone) && !child.eq_ignore_ascii_case(zone)
+}
@@ -0,0 +1,313 @@
+//! Parsing of the blocklists found in the wild, which come in three flavours:
+//!
+//! - hosts files: `0.0.0.0 ads.example.com`, possibly with several names per line
+//! - plain lists: `ads.example.com`, one domain per line
+//! - Adblock filters: `||ads.example.com^` to block, `@@||allowed.example.com^` to allow
+//!
+//! On top of domains, names can be matched by globs (`*.telemetry.example.com`) and regular
+//! expressions written between slashes (`/^ad[0-9]+\./`), both being allowed too when prefixed
+//! with `@@`.
+//!
+//! The format is guessed line by line, so that lists mixing them are understood too.
+
+use std::fmt::{self, Formatter};
+use std::net::IpAddr;
+
+use regex::Regex;
+
+use crate::result::{Error, Result};
+
+/// Names commonly found in hosts files, which must never be blocked
+const HOSTS_RESERVED: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+/// What a rule applies to
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// The domain and its subdomains
+    Domain(String),
+    /// The names matched by the expression, which is kept in its original form (glob or regex)
+    /// to be able to tell which rule matched
+    Regex { source: String, regex: Regex },
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Domain(domain) => write!(f, "{}", domain),
+            Pattern::Regex { source, .. } => write!(f, "{}", source),
+        }
+    }
+}
+
+/// A single entry of a blocklist
+#[derive(Debug, Clone)]
+pub enum Rule {
+    /// The names matching the pattern are blocked
+    Deny(Pattern),
+    /// The names matching the pattern are never blocked
+    Allow(Pattern),
+}
+
+/// What became of the entries of a list while loading it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListStats {
+    pub accepted: usize,
+    pub rejected: usize,
+    pub duplicated: usize,
+}
+
+impl fmt::Display for ListStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} accepted, {} rejected, {} duplicated",
+            self.accepted, self.rejected, self.duplicated
+        )
+    }
+}
+
+/// Parses a line of a blocklist into its rules. Comments and empty lines have none, lines
+/// which cannot be understood have a single error.
+pub fn parse_line(line: &str) -> Vec<Result<Rule>> {
+    let line = line.trim();
+
+    // Comments of hosts files and plain lists, comments and header of Adblock filters
+    if line.is_empty() || line.starts_with('#') || line.starts_with('!') || line.starts_with('[') {
+        return Vec::new();
+    }
+
+    if let Some(rule) = line.strip_prefix("@@") {
+        return vec![parse_pattern(rule).map(Rule::Allow)];
+    }
+    if line.starts_with("||") || line.starts_with('/') {
+        return vec![parse_pattern(line).map(Rule::Deny)];
+    }
+
+    // Drop the trailing comment of hosts files entries
+    let line = match line.split_once('#') {
+        Some((line, _)) => line,
+        None => line,
+    };
+    let mut tokens = line.split_whitespace();
+    let first = match tokens.next() {
+        Some(first) => first,
+        None => return Vec::new(),
+    };
+
+    // Hosts file, every name following the address is blocked
+    if first.parse::<IpAddr>().is_ok() {
+        let names: Vec<Result<Rule>> = tokens
+            .filter(|name| !HOSTS_RESERVED.contains(&name.to_lowercase().as_str()))
+            .map(|name| normalize_domain(name).map(|name| Rule::Deny(Pattern::Domain(name))))
+            .collect();
+
+        return names;
+    }
+
+    // Plain list, a single domain per line
+    if tokens.next().is_some() {
+        return vec![Err(Error::InvalidListEntry(line.to_owned()))];
+    }
+    vec![parse_pattern(first).map(Rule::Deny)]
+}
+
+/// Parses a single pattern, which is either:
+///
+/// - a regular expression between slashes: `/^ad[0-9]+\./`
+/// - a basic Adblock rule, `||domain^`, where the domain can be a glob
+/// - a domain or a glob: `ads.example.com`, `*.telemetry.example.com`
+///
+/// Adblock rules with options or paths cannot be enforced by a DNS server and are rejected.
+fn parse_pattern(rule: &str) -> Result<Pattern> {
+    let invalid = || Error::InvalidListEntry(rule.to_owned());
+
+    if let Some(expr) = rule.strip_prefix('/').and_then(|r| r.strip_suffix('/')) {
+        // Names are always matched in lowercase
+        let regex = Regex::new(&format!("(?i){}", expr)).map_err(|_| invalid())?;
+        return Ok(Pattern::Regex {
+            source: rule.to_owned(),
+            regex,
+        });
+    }
+
+    let domain = match rule.strip_prefix("||") {
+        Some(rule) => rule.strip_suffix('^').ok_or_else(invalid)?,
+        None => rule,
+    };
+
+    if domain.contains('*') {
+        return parse_glob(domain);
+    }
+
+    normalize_domain(domain).map(Pattern::Domain)
+}
+
+/// Turns a glob, where `*` stands for any sequence of characters, into a regular expression
+/// matching whole names.
+fn parse_glob(glob: &str) -> Result<Pattern> {
+    let invalid = || Error::InvalidListEntry(glob.to_owned());
+
+    let glob = glob.trim().trim_end_matches('.').to_lowercase();
+    // Everything but the wildcards must look like a domain
+    let valid = glob.split('*').all(|part| {
+        part.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+    });
+    if !valid || glob.chars().all(|c| c == '*' || c == '.') {
+        return Err(invalid());
+    }
+
+    let expr = glob
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(".*");
+    let regex = Regex::new(&format!("^{}$", expr)).map_err(|_| invalid())?;
+
+    Ok(Pattern::Regex {
+        source: glob,
+        regex,
+    })
+}
+
+/// Lowercases `domain` and converts it to its ASCII form if it is an internationalized one, then
+/// makes sure it is a valid domain name.
+pub fn normalize_domain(domain: &str) -> Result<String> {
+    let invalid = || Error::InvalidListEntry(domain.to_owned());
+
+    let trimmed = domain.trim().trim_end_matches('.');
+    let ascii = if trimmed.is_ascii() {
+        trimmed.to_lowercase()
+    } else {
+        idna::domain_to_ascii(trimmed).map_err(|_| invalid())?
+    };
+
+    // See RFC1035#2.3.4, underscores are tolerated as they are common in practice
+    let valid = !ascii.is_empty()
+        && ascii.len() <= 253
+        && ascii.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+        // An address is not a domain
+        && ascii.parse::<IpAddr>().is_err();
+
+    if !valid {
+        return Err(invalid());
+    }
+
+    Ok(ascii)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocklist::Blocklist;
+
+    /// The rules of `line`, written `deny <pattern>` or `allow <pattern>`, `invalid` for errors
+    fn rules(line: &str) -> Vec<String> {
+        parse_line(line)
+            .into_iter()
+            .map(|rule| match rule {
+                Ok(Rule::Deny(pattern)) => format!("deny {}", pattern),
+                Ok(Rule::Allow(pattern)) => format!("allow {}", pattern),
+                Err(_) => "invalid".to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn comments_have_no_rule() {
+        for line in [
+            "",
+            "   ",
+            "# hosts",
+            "! Title: filters",
+            "[Adblock Plus 2.0]",
+        ] {
+            assert!(rules(line).is_empty(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn hosts_lines() {
+        assert_eq!(
+            rules("0.0.0.0 ads.example.com Tracker.example.com # both"),
+            ["deny ads.example.com", "deny tracker.example.com"]
+        );
+        assert!(rules("0.0.0.0 0.0.0.0").is_empty());
+        assert!(rules("127.0.0.1 localhost").is_empty());
+        assert!(rules(":: ip6-localhost ip6-loopback").is_empty());
+        assert_eq!(rules("0.0.0.0 -bad-.example.com"), ["invalid"]);
+    }
+
+    #[test]
+    fn plain_lines() {
+        assert_eq!(rules("ads.example.com."), ["deny ads.example.com"]);
+        assert_eq!(rules("ads.example.com tracker.example.com"), ["invalid"]);
+        assert_eq!(rules("192.168.1.1/24"), ["invalid"]);
+    }
+
+    #[test]
+    fn adblock_lines() {
+        assert_eq!(rules("||ads.example.com^"), ["deny ads.example.com"]);
+        assert_eq!(rules("@@||good.example.com^"), ["allow good.example.com"]);
+        assert_eq!(rules("||ads.example.com^$third-party"), ["invalid"]);
+        assert_eq!(rules("||ads.example.com/banner.gif"), ["invalid"]);
+    }
+
+    #[test]
+    fn idn_names_become_punycode() {
+        assert_eq!(rules("bücher.example"), ["deny xn--bcher-kva.example"]);
+        assert_eq!(
+            rules("0.0.0.0 BÜCHER.example"),
+            ["deny xn--bcher-kva.example"]
+        );
+        assert_eq!(
+            rules("@@||bücher.example^"),
+            ["allow xn--bcher-kva.example"]
+        );
+    }
+
+    #[test]
+    fn duplicates_are_counted() {
+        let path = std::env::temp_dir().join(format!("barthez-list-{}.txt", std::process::id()));
+        let list = "# mixed formats\n\
+                    ads.example.com\n\
+                    0.0.0.0 ads.example.com tracker.example.com\n\
+                    ||ADS.example.com^\n\
+                    ads.example.com.\n\
+                    not a domain\n\
+                    @@||ads.example.com^\n";
+        std::fs::write(&path, list).unwrap();
+
+        let mut blocklist = Blocklist::new("mixed", None);
+        let stats = blocklist.load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let stats = stats.unwrap();
+        assert_eq!(stats.accepted, 3);
+        assert_eq!(stats.duplicated, 3);
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(blocklist.len(), 2);
+        assert_eq!(blocklist.allowed_len(), 1);
+    }
+}
@@ -0,0 +1,125 @@
+//! Temporary pauses of blocking, to get past a site broken by a list without editing it.
+//!
+//! Pauses are kept apart from the policies, so that they outlive a reload of the lists and
+//! groups. They end by themselves once their time is up: they are only checked when a query
+//! comes in, which is the only time it matters.
+
+use std::collections::HashMap;
+use std::fmt::{self, Formatter};
+use std::time::{Duration, Instant};
+
+/// When a pause ends, `None` being until blocking is resumed by hand
+type Deadline = Option<Instant>;
+
+/// A pause in effect, as reported to whoever asks
+#[derive(Debug, Clone)]
+pub struct PauseStatus {
+    /// The group whose blocking is paused, `None` for every client
+    pub group: Option<String>,
+    /// How long until blocking is resumed, `None` if it must be resumed by hand
+    pub remaining: Option<Duration>,
+}
+
+impl fmt::Display for PauseStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.group {
+            Some(group) => write!(f, "paused for {}", group)?,
+            None => write!(f, "paused for everyone")?,
+        }
+        match self.remaining {
+            Some(remaining) => write!(f, ", {}s left", remaining.as_secs()),
+            None => write!(f, ", until resumed"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Pauses {
+    global: Option<Deadline>,
+    groups: HashMap<String, Deadline>,
+}
+
+impl Pauses {
+    /// Pauses blocking for `group`, or every client if `None`, for `duration` or until resumed.
+    /// A duration too long to have a deadline also lasts until resumed. Pausing again replaces
+    /// the previous deadline.
+    pub fn pause(&mut self, group: Option<&str>, duration: Option<Duration>) {
+        let deadline = duration.and_then(|duration| Instant::now().checked_add(duration));
+        match group {
+            Some(group) => {
+                self.groups.insert(group.to_owned(), deadline);
+            }
+            None => self.global = Some(deadline),
+        }
+    }
+
+    /// Resumes blocking for `group`. Resuming for every client also ends the pauses of the
+    /// groups.
+    pub fn resume(&mut self, group: Option<&str>) {
+        match group {
+            Some(group) => {
+                self.groups.remove(group);
+            }
+            None => {
+                self.global = None;
+                self.groups.clear();
+            }
+        }
+    }
+
+    /// Tells whether blocking is paused for the clients of `group`
+    pub fn is_paused(&mut self, group: &str) -> bool {
+        self.expire();
+        self.global.is_some() || self.groups.contains_key(group)
+    }
+
+    /// Every pause still in effect
+    pub fn status(&mut self) -> Vec<PauseStatus> {
+        self.expire();
+
+        let now = Instant::now();
+        let remaining = |deadline: &Deadline| deadline.map(|deadline| deadline - now);
+
+        let global = self.global.as_ref().map(|deadline| PauseStatus {
+            group: None,
+            remaining: remaining(deadline),
+        });
+        let groups = self.groups.iter().map(|(group, deadline)| PauseStatus {
+            group: Some(group.clone()),
+            remaining: remaining(deadline),
+        });
+
+        global.into_iter().chain(groups).collect()
+    }
+
+    /// Drops the pauses whose time is up
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let expired = |deadline: &Deadline| deadline.is_some_and(|deadline| deadline <= now);
+
+        if self.global.as_ref().is_some_and(expired) {
+            println!("Blocking resumed for everyone");
+            self.global = None;
+        }
+        self.groups.retain(|group, deadline| {
+            if expired(deadline) {
+                println!("Blocking resumed for {}", group);
+            }
+            !expired(deadline)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_pauses_last_until_resumed() {
+        let mut pauses = Pauses::default();
+        pauses.pause(Some("kids"), Some(Duration::from_secs(u64::MAX)));
+
+        assert!(pauses.is_paused("kids"));
+        assert_eq!(pauses.status()[0].remaining, None);
+    }
+}
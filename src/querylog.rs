@@ -0,0 +1,355 @@
+//! Log of every query handled, kept on disk as JSON lines, one file per day:
+//!
+//! ```text
+//! {"timestamp":"2024-05-01T12:00:00.000Z","client":"192.168.1.10","name":"example.com",...}
+//! ```
+//!
+//! Entries are written by a thread of their own, so that a slow disk never holds a query back.
+//! If it can't keep up, entries are dropped rather than queued forever.
+
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+use crate::globals::{QUERY_LOG_QUEUE, RECENT_QUERIES};
+use crate::record::{Record, RecordType};
+use crate::result::{Error, Result, ResultCode};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// What happened to a query
+#[derive(Clone)]
+pub struct QueryEntry {
+    pub time: SystemTime,
+    pub client: SocketAddr,
+    pub name: String,
+    pub qtype: RecordType,
+    pub rcode: ResultCode,
+    pub answers: Vec<Record>,
+    /// The server the answer came from, `None` if it didn't come from the network
+    pub upstream: Option<SocketAddr>,
+    /// Whether the answer came from the cache
+    pub cached: bool,
+    pub blocked: bool,
+    /// The rule which blocked or allowed the name, if any
+    pub rule: Option<String>,
+    /// Time spent answering the query
+    pub elapsed: Duration,
+}
+
+/// How much of the clients is written to the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientPrivacy {
+    /// Their address as is
+    Show,
+    /// A hash of their address, so that the queries of a client can still be told apart. The
+    /// hash is salted with a secret drawn at startup, it changes every time the server restarts.
+    Hash,
+    /// Nothing at all
+    Hide,
+}
+
+impl FromStr for ClientPrivacy {
+    type Err = Error;
+
+    /// Parses `show`, `hash` or `hide`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "show" => Ok(ClientPrivacy::Show),
+            "hash" => Ok(ClientPrivacy::Hash),
+            "hide" => Ok(ClientPrivacy::Hide),
+            _ => Err(Error::InvalidClientPrivacy(s.to_owned())),
+        }
+    }
+}
+
+/// Hides the clients as the privacy mode says, wherever they are recorded
+#[derive(Clone)]
+pub struct Anonymizer {
+    privacy: ClientPrivacy,
+    /// Makes the hashes differ from one run to the next
+    salt: RandomState,
+}
+
+impl Anonymizer {
+    pub fn new(privacy: ClientPrivacy) -> Self {
+        Self {
+            privacy,
+            salt: RandomState::new(),
+        }
+    }
+
+    /// How `ip` is recorded, if at all
+    pub fn client(&self, ip: IpAddr) -> Option<String> {
+        let ip = ip.to_canonical();
+        match self.privacy {
+            ClientPrivacy::Show => Some(ip.to_string()),
+            ClientPrivacy::Hash => Some(format!("{:016x}", self.salt.hash_one(ip))),
+            ClientPrivacy::Hide => None,
+        }
+    }
+}
+
+/// An entry as written to disk
+#[derive(Serialize)]
+struct LogLine<'a> {
+    timestamp: String,
+    client: Option<String>,
+    name: &'a str,
+    #[serde(rename = "type")]
+    qtype: String,
+    rcode: String,
+    answers: Vec<String>,
+    upstream: Option<String>,
+    cached: bool,
+    blocked: bool,
+    rule: Option<&'a str>,
+    elapsed_ms: f64,
+}
+
+impl<'a> LogLine<'a> {
+    fn new(entry: &'a QueryEntry, anonymizer: &Anonymizer) -> Self {
+        Self {
+            timestamp: timestamp(entry.time),
+            client: anonymizer.client(entry.client.ip()),
+            name: &entry.name,
+            qtype: entry.qtype.to_string(),
+            rcode: entry.rcode.to_string(),
+            answers: entry.answers.iter().map(describe).collect(),
+            upstream: entry.upstream.map(|upstream| upstream.to_string()),
+            cached: entry.cached,
+            blocked: entry.blocked,
+            rule: entry.rule.as_deref(),
+            elapsed_ms: entry.elapsed.as_micros() as f64 / 1000.0,
+        }
+    }
+}
+
+/// Handle on the thread writing the log, which also keeps the last entries in memory
+pub struct QueryLog {
+    sender: Sender<QueryEntry>,
+    anonymizer: Anonymizer,
+    recent: Mutex<VecDeque<QueryEntry>>,
+}
+
+impl QueryLog {
+    /// Starts writing the log to `dir`, where the files older than `retention_days` are
+    /// deleted, and clients are recorded as `anonymizer` says.
+    pub fn start(dir: &Path, retention_days: u64, anonymizer: Anonymizer) -> Result<Self> {
+        fs::create_dir_all(dir).map_err(|_| Error::InvalidInputPath)?;
+
+        let (sender, receiver) = mpsc::channel(QUERY_LOG_QUEUE);
+        let writer = LogWriter {
+            dir: dir.to_owned(),
+            retention_days,
+            anonymizer: anonymizer.clone(),
+            file: None,
+        };
+        tokio::task::spawn_blocking(move || writer.run(receiver));
+
+        Ok(Self {
+            sender,
+            anonymizer,
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_QUERIES)),
+        })
+    }
+
+    /// The last `limit` entries, the most recent first, as they are written to disk
+    pub fn recent(&self, limit: usize) -> Vec<serde_json::Value> {
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent
+            .iter()
+            .rev()
+            .take(limit)
+            .filter_map(|entry| serde_json::to_value(LogLine::new(entry, &self.anonymizer)).ok())
+            .collect()
+    }
+
+    /// Queues `entry` to be written
+    pub fn record(&self, entry: QueryEntry) {
+        {
+            let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+            if recent.len() >= RECENT_QUERIES {
+                recent.pop_front();
+            }
+            recent.push_back(entry.clone());
+        }
+
+        match self.sender.try_send(entry) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => eprintln!("Query log is lagging, dropping an entry"),
+            Err(TrySendError::Closed(_)) => eprintln!("Query log is closed, dropping an entry"),
+        }
+    }
+}
+
+struct LogWriter {
+    dir: PathBuf,
+    retention_days: u64,
+    anonymizer: Anonymizer,
+    /// The file being written and the day it is for, in days since the epoch
+    file: Option<(u64, BufWriter<File>)>,
+}
+
+impl LogWriter {
+    fn run(mut self, mut receiver: Receiver<QueryEntry>) {
+        while let Some(entry) = receiver.blocking_recv() {
+            if let Err(e) = self.write(&entry) {
+                eprintln!("Failed writing the query log: {}", e);
+                continue;
+            }
+
+            // Write everything queued in one go before flushing
+            while let Ok(entry) = receiver.try_recv() {
+                if let Err(e) = self.write(&entry) {
+                    eprintln!("Failed writing the query log: {}", e);
+                }
+            }
+            if let Some((_, file)) = self.file.as_mut() {
+                if let Err(e) = file.flush() {
+                    eprintln!("Failed writing the query log: {}", e);
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &QueryEntry) -> io::Result<()> {
+        let secs = entry
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let day = secs / SECONDS_PER_DAY;
+
+        if !matches!(self.file, Some((current, _)) if current == day) {
+            self.rotate(day)?;
+        }
+
+        let line = LogLine::new(entry, &self.anonymizer);
+        if let Some((_, file)) = self.file.as_mut() {
+            serde_json::to_writer(&mut *file, &line)?;
+            file.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves on to the file of `day`, and deletes the files which are too old
+    fn rotate(&mut self, day: u64) -> io::Result<()> {
+        if let Some((_, mut file)) = self.file.take() {
+            file.flush()?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(file_name(day)))?;
+        self.file = Some((day, BufWriter::new(file)));
+
+        // File names sort by date, anything before the oldest one to keep goes
+        let oldest = file_name(day.saturating_sub(self.retention_days));
+        for file in fs::read_dir(&self.dir)?.flatten() {
+            let name = file.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("queries-") && name.ends_with(".jsonl") && *name < *oldest {
+                println!("Deleting old query log {}", name);
+                fs::remove_file(file.path())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The record as it would be written in a zone file, without its class
+pub fn describe(record: &Record) -> String {
+    let preamble = record.preamble();
+    let data = match record {
+        Record::A { addr, .. } => addr.to_string(),
+        Record::AAAA { addr, .. } => addr.to_string(),
+        Record::NS { host, .. } | Record::CNAME { host, .. } | Record::PTR { host, .. } => {
+            host.clone()
+        }
+        Record::MX {
+            preference,
+            exchange,
+            ..
+        } => format!("{} {}", preference, exchange),
+        Record::SOA {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+            ..
+        } => format!(
+            "{} {} {} {} {} {} {}",
+            mname, rname, serial, refresh, retry, expire, minimum
+        ),
+        Record::Unknown { .. } | Record::OPT { .. } => String::new(),
+    };
+
+    format!(
+        "{} {} {} {}",
+        preamble.name,
+        preamble.ttl(),
+        preamble.record_type(),
+        data
+    )
+    .trim_end()
+    .to_owned()
+}
+
+fn file_name(day: u64) -> String {
+    let (year, month, day) = civil_from_days(day);
+    format!("queries-{:04}-{:02}-{:02}.jsonl", year, month, day)
+}
+
+/// Formats `time` as in RFC3339, in UTC and with milliseconds
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days(secs / SECONDS_PER_DAY);
+    let secs = secs % SECONDS_PER_DAY;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Turns a number of days since the epoch into a date of the proleptic Gregorian calendar, see
+/// [chrono-Compatible Low-Level Date Algorithms](https://howardhinnant.github.io/date_algorithms.html#civil_from_days).
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Shift the epoch to 0000-03-01, so that leap days end the 400 years eras
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    (year, month, day)
+}
@@ -6,7 +6,8 @@ use crate::PacketBuffer;
 
 // #![allow(non_camel_case_types)]
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
 pub enum RecordType {
     Unknown(u16),
     A,  // 1
@@ -14,10 +15,13 @@ pub enum RecordType {
     //#[allow(non_camel_case_types)]
     #[allow(non_camel_case_types)]
     CNAME, // 5
+    SOA, // 6
+    PTR, // 12
     //#[allow(non_camel_case_types)]
     MX, // 15
     #[allow(non_camel_case_types)]
     AAAA, // 28
+    OPT, // 41
 }
 
 impl From<RecordType> for u16 {
@@ -26,8 +30,11 @@ impl From<RecordType> for u16 {
             RecordType::A => 1,
             RecordType::NS => 2,
             RecordType::CNAME => 5,
+            RecordType::SOA => 6,
+            RecordType::PTR => 12,
             RecordType::MX => 15,
             RecordType::AAAA => 28,
+            RecordType::OPT => 41,
             RecordType::Unknown(x) => x,
         }
     }
@@ -39,8 +46,11 @@ impl From<u16> for RecordType {
             1 => RecordType::A,
             2 => RecordType::NS,
             5 => RecordType::CNAME,
+            6 => RecordType::SOA,
+            12 => RecordType::PTR,
             15 => RecordType::MX,
             28 => RecordType::AAAA,
+            41 => RecordType::OPT,
             _ => RecordType::Unknown(value),
         }
     }
@@ -53,14 +63,78 @@ impl fmt::Display for RecordType {
             RecordType::A => write!(f, "A")?,
             RecordType::NS => write!(f, "NS")?,
             RecordType::CNAME => write!(f, "CNAME")?,
+            RecordType::SOA => write!(f, "SOA")?,
+            RecordType::PTR => write!(f, "PTR")?,
             RecordType::MX => write!(f, "MX")?,
             RecordType::AAAA => write!(f, "AAAA")?,
+            RecordType::OPT => write!(f, "OPT")?,
         }
 
         Ok(())
     }
 }
 
+/// The option codes which can be carried by an `OPT` record, from the
+/// [IANA registry](https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-11).
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum EdnsOptionCode {
+    Unknown(u16),
+    Nsid,         // 3
+    ClientSubnet, // 8
+    Cookie,       // 10
+    KeepAlive,    // 11
+    Padding,      // 12
+}
+
+impl From<EdnsOptionCode> for u16 {
+    fn from(value: EdnsOptionCode) -> Self {
+        match value {
+            EdnsOptionCode::Nsid => 3,
+            EdnsOptionCode::ClientSubnet => 8,
+            EdnsOptionCode::Cookie => 10,
+            EdnsOptionCode::KeepAlive => 11,
+            EdnsOptionCode::Padding => 12,
+            EdnsOptionCode::Unknown(x) => x,
+        }
+    }
+}
+
+impl From<u16> for EdnsOptionCode {
+    fn from(value: u16) -> Self {
+        match value {
+            3 => EdnsOptionCode::Nsid,
+            8 => EdnsOptionCode::ClientSubnet,
+            10 => EdnsOptionCode::Cookie,
+            11 => EdnsOptionCode::KeepAlive,
+            12 => EdnsOptionCode::Padding,
+            _ => EdnsOptionCode::Unknown(value),
+        }
+    }
+}
+
+impl fmt::Display for EdnsOptionCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EdnsOptionCode::Unknown(x) => write!(f, "Unknown({x})")?,
+            EdnsOptionCode::Nsid => write!(f, "NSID")?,
+            EdnsOptionCode::ClientSubnet => write!(f, "ECS")?,
+            EdnsOptionCode::Cookie => write!(f, "COOKIE")?,
+            EdnsOptionCode::KeepAlive => write!(f, "KEEPALIVE")?,
+            EdnsOptionCode::Padding => write!(f, "PADDING")?,
+        }
+
+        Ok(())
+    }
+}
+
+/// A single option of an `OPT` record, kept as raw data.
+#[derive(Debug, Clone)]
+pub struct EdnsOption {
+    pub code: EdnsOptionCode,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone)]
 pub struct RecordPreamble {
     pub name: String,
     /// 2 bytes
@@ -71,6 +145,30 @@ pub struct RecordPreamble {
     len: u16,
 }
 
+impl RecordPreamble {
+    pub fn new(name: &str, record_type: RecordType, class: u16, ttl: u32) -> Self {
+        Self {
+            name: name.to_owned(),
+            record_type,
+            _class: class,
+            ttl,
+            len: 0,
+        }
+    }
+
+    pub fn record_type(&self) -> RecordType {
+        self.record_type
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    pub fn set_ttl(&mut self, ttl: u32) {
+        self.ttl = ttl;
+    }
+}
+
 impl fmt::Display for RecordPreamble {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         writeln!(f, "\tName: {}", self.name)?;
@@ -83,9 +181,13 @@ impl fmt::Display for RecordPreamble {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub enum Record {
+    /// A record of a type which isn't parsed, kept as raw data so that it can be written back
     Unknown {
         preamble: RecordPreamble,
+        data: Vec<u8>,
     },
     A {
         preamble: RecordPreamble,
@@ -100,6 +202,25 @@ pub enum Record {
         preamble: RecordPreamble,
         host: String,
     },
+    /// Name an address maps back to, see [RFC1035#3.3.12](https://www.rfc-editor.org/rfc/rfc1035#section-3.3.12).
+    PTR {
+        preamble: RecordPreamble,
+        host: String,
+    },
+    /// Start of a zone of authority, see [RFC1035#3.3.13](https://www.rfc-editor.org/rfc/rfc1035#section-3.3.13).
+    SOA {
+        preamble: RecordPreamble,
+        /// The primary name server of the zone
+        mname: String,
+        /// The mailbox of the person responsible for the zone
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        /// Used as the TTL of negative answers, see [RFC2308#4](https://www.rfc-editor.org/rfc/rfc2308#section-4)
+        minimum: u32,
+    },
     MX {
         preamble: RecordPreamble,
         preference: u16,
@@ -110,9 +231,63 @@ pub enum Record {
         preamble: RecordPreamble,
         addr: Ipv6Addr,
     },
+    /// EDNS(0) pseudo-record, see [RFC6891#6.1.2](https://www.rfc-editor.org/rfc/rfc6891#section-6.1.2).
+    /// The CLASS and TTL fields of the preamble are reused to carry the fields below.
+    OPT {
+        preamble: RecordPreamble,
+        /// 2 bytes (CLASS). The largest UDP payload the sender can reassemble.
+        udp_payload_size: u16,
+        /// 1 byte. Upper 8 bits of the 12 bits extended RCODE.
+        extended_rcode: u8,
+        /// 1 byte. The EDNS version, only 0 is defined.
+        version: u8,
+        /// 1 bit. Set when the sender supports DNSSEC ("DNSSEC OK").
+        dnssec_ok: bool,
+        options: Vec<EdnsOption>,
+    },
 }
 
 impl Record {
+    /// Builds an `OPT` record advertising `udp_payload_size`, without any option.
+    pub fn new_opt(udp_payload_size: u16, extended_rcode: u8, dnssec_ok: bool) -> Self {
+        Record::OPT {
+            preamble: RecordPreamble::new("", RecordType::OPT, udp_payload_size, 0),
+            udp_payload_size,
+            extended_rcode,
+            version: 0,
+            dnssec_ok,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn preamble(&self) -> &RecordPreamble {
+        match self {
+            Record::Unknown { preamble, .. }
+            | Record::A { preamble, .. }
+            | Record::NS { preamble, .. }
+            | Record::CNAME { preamble, .. }
+            | Record::PTR { preamble, .. }
+            | Record::SOA { preamble, .. }
+            | Record::MX { preamble, .. }
+            | Record::AAAA { preamble, .. }
+            | Record::OPT { preamble, .. } => preamble,
+        }
+    }
+
+    pub fn preamble_mut(&mut self) -> &mut RecordPreamble {
+        match self {
+            Record::Unknown { preamble, .. }
+            | Record::A { preamble, .. }
+            | Record::NS { preamble, .. }
+            | Record::CNAME { preamble, .. }
+            | Record::PTR { preamble, .. }
+            | Record::SOA { preamble, .. }
+            | Record::MX { preamble, .. }
+            | Record::AAAA { preamble, .. }
+            | Record::OPT { preamble, .. } => preamble,
+        }
+    }
+
     /// From [RFC1035#4.1.3](https://www.rfc-editor.org/rfc/rfc1035#section-4.1.3):
     /// ```
     ///                                     1  1  1  1  1  1
@@ -165,7 +340,7 @@ impl Record {
                 let pos = buffer.pos();
                 buffer.write_u16(0)?;
                 buffer.write_qname(host)?;
-                let size = buffer.pos() - pos + 2;
+                let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
             Record::CNAME { preamble, host } => {
@@ -179,7 +354,50 @@ impl Record {
                 let pos = buffer.pos();
                 buffer.write_u16(0)?;
                 buffer.write_qname(host)?;
-                let size = buffer.pos() - pos + 2;
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::PTR { preamble, host } => {
+                buffer.write_qname(&preamble.name)?;
+                buffer.write_u16(RecordType::PTR.into())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(preamble.ttl)?;
+
+                // We don't know the size of the qname yet,
+                // so we write an empty 2 bytes word for now
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+                buffer.write_qname(host)?;
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::SOA {
+                preamble,
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                buffer.write_qname(&preamble.name)?;
+                buffer.write_u16(RecordType::SOA.into())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(preamble.ttl)?;
+
+                // We don't know the size of the qnames yet,
+                // so we write an empty 2 bytes word for now
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(*serial)?;
+                buffer.write_u32(*refresh)?;
+                buffer.write_u32(*retry)?;
+                buffer.write_u32(*expire)?;
+                buffer.write_u32(*minimum)?;
+                let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
             Record::MX {
@@ -200,7 +418,7 @@ impl Record {
                 buffer.write_u16(*preference)?;
                 buffer.write_qname(exchange)?;
                 // Calculate and set the length of the data we just wrote
-                let size = buffer.pos() - pos + 2;
+                let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
             Record::AAAA { preamble, addr } => {
@@ -215,8 +433,45 @@ impl Record {
                     buffer.write_u16(segment)?;
                 }
             }
-            _ => {
-                println!("Skipping writing record: {}", self);
+            Record::OPT {
+                udp_payload_size,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                options,
+                ..
+            } => {
+                // The owner name is always the root
+                buffer.write_qname("")?;
+                buffer.write_u16(RecordType::OPT.into())?;
+                buffer.write_u16(*udp_payload_size)?;
+                buffer.write_u8(*extended_rcode)?;
+                buffer.write_u8(*version)?;
+                buffer.write_u16((*dnssec_ok as u16) << 15)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+                for option in options {
+                    buffer.write_u16(option.code.into())?;
+                    buffer.write_u16(option.data.len() as u16)?;
+                    for b in &option.data {
+                        buffer.write_u8(*b)?;
+                    }
+                }
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::Unknown { preamble, data } => {
+                buffer.write_qname(&preamble.name)?;
+                buffer.write_u16(preamble.record_type.into())?;
+                buffer.write_u16(preamble._class)?;
+                buffer.write_u32(preamble.ttl)?;
+
+                // The data is written back as it was read
+                buffer.write_u16(data.len() as u16)?;
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
 
@@ -227,9 +482,10 @@ impl Record {
 impl fmt::Display for Record {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Record::Unknown { preamble } => {
+            Record::Unknown { preamble, data } => {
                 writeln!(f, "Record::Unknown {{")?;
                 write!(f, "{}", preamble)?;
+                writeln!(f, "\tdata: {} bytes", data.len())?;
                 writeln!(f, "}}")?;
             }
             Record::NS { preamble, host } => {
@@ -244,6 +500,33 @@ impl fmt::Display for Record {
                 write!(f, "\t{}", host)?;
                 writeln!(f, "}}")?;
             }
+            Record::PTR { preamble, host } => {
+                writeln!(f, "Record::PTR {{")?;
+                write!(f, "{}", preamble)?;
+                write!(f, "\t{}", host)?;
+                writeln!(f, "}}")?;
+            }
+            Record::SOA {
+                preamble,
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                writeln!(f, "Record::SOA {{")?;
+                write!(f, "{}", preamble)?;
+                writeln!(f, "\tmname: {}", mname)?;
+                writeln!(f, "\trname: {}", rname)?;
+                writeln!(f, "\tserial: {}", serial)?;
+                writeln!(f, "\trefresh: {}", refresh)?;
+                writeln!(f, "\tretry: {}", retry)?;
+                writeln!(f, "\texpire: {}", expire)?;
+                writeln!(f, "\tminimum: {}", minimum)?;
+                writeln!(f, "}}")?;
+            }
             Record::MX {
                 preamble,
                 preference,
@@ -267,6 +550,25 @@ impl fmt::Display for Record {
                 writeln!(f, "\taddr: {}", addr)?;
                 writeln!(f, "}}")?;
             }
+            Record::OPT {
+                preamble,
+                udp_payload_size,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                options,
+            } => {
+                writeln!(f, "Record::OPT {{")?;
+                write!(f, "{}", preamble)?;
+                writeln!(f, "\tudp_payload_size: {}", udp_payload_size)?;
+                writeln!(f, "\textended_rcode: {}", extended_rcode)?;
+                writeln!(f, "\tversion: {}", version)?;
+                writeln!(f, "\tdnssec_ok: {}", if *dnssec_ok { "1" } else { "0" })?;
+                for option in options {
+                    writeln!(f, "\toption: {} ({} bytes)", option.code, option.data.len())?;
+                }
+                writeln!(f, "}}")?;
+            }
         }
 
         Ok(())
@@ -308,6 +610,29 @@ impl TryFrom<&mut PacketBuffer> for Record {
                 let host = buffer.read_qname()?;
                 Ok(Record::CNAME { preamble, host })
             }
+            RecordType::PTR => {
+                let host = buffer.read_qname()?;
+                Ok(Record::PTR { preamble, host })
+            }
+            RecordType::SOA => {
+                let mname = buffer.read_qname()?;
+                let rname = buffer.read_qname()?;
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+                Ok(Record::SOA {
+                    preamble,
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                })
+            }
             RecordType::MX => {
                 let preference = buffer.read_u16()?;
                 let exchange = buffer.read_qname()?;
@@ -335,11 +660,84 @@ impl TryFrom<&mut PacketBuffer> for Record {
 
                 Ok(Record::AAAA { preamble, addr })
             }
+            RecordType::OPT => {
+                let udp_payload_size = preamble._class;
+                let extended_rcode = (preamble.ttl >> 24) as u8;
+                let version = ((preamble.ttl >> 16) & 0xFF) as u8;
+                let dnssec_ok = (preamble.ttl & 0x8000) != 0;
+
+                // The RDATA is a sequence of {code, length, data} options
+                let end = buffer.pos() + preamble.len as usize;
+                let mut options = Vec::new();
+                while buffer.pos() < end {
+                    let code = EdnsOptionCode::from(buffer.read_u16()?);
+                    let len = buffer.read_u16()? as usize;
+                    let pos = buffer.pos();
+                    let data = buffer.get_range(pos, len)?.to_vec();
+                    buffer.step(len);
+
+                    options.push(EdnsOption { code, data });
+                }
+
+                Ok(Record::OPT {
+                    preamble,
+                    udp_payload_size,
+                    extended_rcode,
+                    version,
+                    dnssec_ok,
+                    options,
+                })
+            }
             _ => {
-                // Jumps over the non-parsed records length
+                // Keeps the data of the non-parsed records as is
+                let pos = buffer.pos();
+                let data = buffer.get_range(pos, preamble.len.into())?.to_vec();
                 buffer.step(preamble.len.into());
-                return Ok(Record::Unknown { preamble });
+                Ok(Record::Unknown { preamble, data })
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(record: &Record) -> (Record, Vec<u8>) {
+        let mut buffer = PacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+        let len = buffer.pos();
+        let bytes = buffer.get_range(0, len).unwrap().to_vec();
+
+        let mut buffer = PacketBuffer::from(bytes.clone());
+        (Record::try_from(&mut buffer).unwrap(), bytes)
+    }
+
+    #[test]
+    fn unknown_records_are_written_back_as_read() {
+        // TXT record with two strings, "v=spf1" and "-all"
+        let mut bytes = vec![
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+        ];
+        bytes.extend_from_slice(&[0, 16, 0, 1, 0, 0, 0x0e, 0x10, 0, 12]);
+        bytes.extend_from_slice(&[
+            6, b'v', b'=', b's', b'p', b'f', b'1', 4, b'-', b'a', b'l', b'l',
+        ]);
+        let record = Record::try_from(&mut PacketBuffer::from(bytes.clone())).unwrap();
+
+        let (parsed, written) = round_trip(&record);
+        assert_eq!(written, bytes);
+        assert!(matches!(parsed, Record::Unknown { data, .. } if data.len() == 12));
+    }
+
+    #[test]
+    fn ptr_records_are_written() {
+        let record = Record::PTR {
+            preamble: RecordPreamble::new("1.1.168.192.in-addr.arpa", RecordType::PTR, 1, 60),
+            host: "printer.corp.example".to_owned(),
+        };
+
+        let (parsed, _) = round_trip(&record);
+        assert!(matches!(parsed, Record::PTR { host, .. } if host == "printer.corp.example"));
+    }
+}
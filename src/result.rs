@@ -6,7 +6,8 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     PacketBufferInvalidPosition,
-    PacketBufferOver512(String),
+    /// When reading or writing past the end of a `PacketBuffer`, carries the limit in use
+    PacketBufferExhausted(usize, String),
 
     /// When reading labels performs too many jumps
     MaxJumpsAttained,
@@ -16,19 +17,78 @@ pub enum Error {
 
     /// When `read()` on a `std::io::File` fails
     FailedReadingFile,
+    FailedWritingFile,
     // Packet write error
     // FailedWritingBuffer(String),
     LabelLengthOver63,
 
+    /// When a blocking mode cannot be parsed
+    InvalidBlockingMode(String),
+    /// When a line of a blocklist cannot be understood
+    InvalidListEntry(String),
+    /// When a client of a group is neither an address, a network nor a MAC address
+    InvalidClient(String),
+    /// When a group is referred to but doesn't exist
+    UnknownGroup(String),
+    /// When the privacy mode of the query log is none of `show`, `hash` or `hide`
+    InvalidClientPrivacy(String),
+    /// When a file is referred to as a list but isn't one of those in use
+    UnknownList(String),
+    /// When the configuration can't be read, or has a wrong value
+    InvalidConfig(String),
+    /// When the resolution mode is neither `recursive` nor `forward`
+    InvalidResolutionMode(String),
+    /// When the strategy to pick upstreams is none of `failover`, `round-robin` or `fastest`
+    InvalidStrategy(String),
+    /// When an upstream isn't an address
+    InvalidUpstream(String),
+    /// When the certificates, name or pin of a TLS or HTTPS upstream can't be used
+    InvalidTlsConfig(String),
+
     UDPBindFailed,
     UDPSendFailed,
     UDPRecvFailed,
+
+    TCPBindFailed,
+    TCPConnectFailed,
+    TCPSendFailed,
+    TCPRecvFailed,
+
+    /// When an upstream can't be reached over TLS, its certificate being refused for instance
+    TlsHandshakeFailed,
+    /// When an HTTPS upstream can't be sent a request, or closed the connection before answering
+    HttpRequestFailed,
+    /// When an HTTPS upstream answers with an error status, or something else than a DNS message
+    UnexpectedHttpResponse(String),
+
+    /// When an upstream server takes too long to answer
+    LookupTimeout,
+    /// When an upstream server answers with another ID or question than the query's
+    MismatchedAnswer,
+    /// When there is no upstream to forward a query to
+    NoUpstream,
+    /// When the server stopped accepting queries
+    ServerShutdown,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Error::PacketBufferOver512(s) => writeln!(f, "Buffer overflow: {s}")?,
+            Error::PacketBufferExhausted(limit, s) => {
+                writeln!(f, "Buffer exhausted (limit {limit}): {s}")?
+            }
+            Error::InvalidBlockingMode(s) => writeln!(f, "Invalid blocking mode: {s}")?,
+            Error::InvalidListEntry(s) => writeln!(f, "Invalid blocklist entry: {s}")?,
+            Error::InvalidClient(s) => writeln!(f, "Invalid client: {s}")?,
+            Error::UnknownGroup(s) => writeln!(f, "Unknown group: {s}")?,
+            Error::InvalidClientPrivacy(s) => writeln!(f, "Invalid client privacy: {s}")?,
+            Error::UnknownList(s) => writeln!(f, "Unknown list: {s}")?,
+            Error::InvalidConfig(s) => writeln!(f, "Invalid configuration: {s}")?,
+            Error::InvalidResolutionMode(s) => writeln!(f, "Invalid resolution mode: {s}")?,
+            Error::InvalidStrategy(s) => writeln!(f, "Invalid upstream strategy: {s}")?,
+            Error::InvalidUpstream(s) => writeln!(f, "Invalid upstream: {s}")?,
+            Error::InvalidTlsConfig(s) => writeln!(f, "Invalid TLS configuration: {s}")?,
+            Error::UnexpectedHttpResponse(s) => writeln!(f, "Unexpected HTTP response: {s}")?,
             _ => writeln!(f, "Error")?,
         }
 
@@ -36,7 +96,7 @@ impl fmt::Display for Error {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 pub enum ResultCode {
     #[default]
     NoError = 0,
@@ -1,74 +1,559 @@
+use crate::blocklist::{BlockingPolicy, Decision};
+use crate::cache::{Cache, CacheStats, CachedEntry};
+use crate::config::Config;
+use crate::forward::{
+    ConditionalForwarders, Forwarder, ResolutionMode, Upstream, UpstreamTransport,
+};
+use crate::globals::{
+    EDNS_PACKET_SIZE, MAX_PACKET_SIZE, STATS_BUCKET, STATS_WINDOWS, STREAM_MAX_PENDING,
+    TCP_IDLE_TIMEOUT, TCP_TIMEOUT, TLS_HANDSHAKE_TIMEOUT, UDP_PACKET_SIZE, UPSTREAM_TIMEOUT,
+};
+use crate::groups::ClientGroups;
+use crate::metrics::Metrics;
 use crate::packet::{Packet, PacketBuffer};
-use crate::record::RecordType;
+use crate::pause::{PauseStatus, Pauses};
+use crate::querylog::{Anonymizer, QueryEntry, QueryLog};
+use crate::record::{Record, RecordType};
 use crate::result::{Error, Result, ResultCode};
+use crate::stats::{Statistics, Summary};
 
 use std::fmt::{self, Formatter};
-use std::net::Ipv4Addr;
-use std::net::UdpSocket;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+/// The transport a query was received on, which bounds the size of its answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// How a lookup went, for the query log
+#[derive(Debug, Default)]
+pub struct LookupTrace {
+    /// Whether the answer came straight from the cache
+    pub cached: bool,
+    /// The server which gave the answer
+    pub upstream: Option<SocketAddr>,
+    /// How many lookups had to be nested to find the answer, 0 if none was needed
+    pub depth: usize,
+}
 
 pub struct Server {
-    local_addr: String,
+    local_addr: IpAddr,
+    /// Source port of upstream lookups, 0 to pick a random one for each of them
     local_port: u16,
+    /// Servers recursion starts from
+    root_servers: Vec<Ipv4Addr>,
+    /// The root server the next recursion starts from, so that they take turns
+    next_root: AtomicUsize,
+    /// Where queries are forwarded to, if not resolved recursively
+    forwarder: Option<Forwarder>,
+    /// Where the names of some domains are forwarded to, before anything else is tried
+    conditional: ConditionalForwarders,
+    /// Bounds the number of queries being resolved at the same time
+    permits: Arc<Semaphore>,
+    /// Records received from upstream servers, shared by every query
+    cache: SyncMutex<Cache>,
+    /// Domains answered locally instead of being resolved, and how, for each group of clients
+    groups: RwLock<ClientGroups>,
+    /// Groups whose blocking is paused, which outlive a change of groups
+    pauses: SyncMutex<Pauses>,
+    /// Where every query handled is recorded, if anywhere
+    query_log: RwLock<Option<QueryLog>>,
+    /// Hides the clients in the statistics and the query log, as `query_log.privacy` says
+    anonymizer: Anonymizer,
+    /// Counters of the queries handled, over the largest of `STATS_WINDOWS`
+    stats: SyncMutex<Statistics>,
+    /// Counters and histograms exported for monitoring
+    metrics: Metrics,
 }
 
 impl Server {
-    pub fn new(addr: String, port: u16) -> Self {
-        Self {
-            local_addr: addr,
-            local_port: port,
+    pub fn new(config: &Config) -> Result<Self> {
+        let stats_retention = STATS_WINDOWS.iter().max().copied().unwrap_or_default();
+        let anonymizer = Anonymizer::new(config.query_log.privacy);
+
+        let mut conditional = ConditionalForwarders::default();
+        for rule in &config.resolver.conditional {
+            let forwarder = Forwarder::new(&rule.upstreams, rule.strategy)?;
+            conditional.add(&rule.domain, forwarder);
+        }
+
+        Ok(Self {
+            local_addr: config.resolver.bind,
+            local_port: config.resolver.port,
+            root_servers: config.resolver.root_servers.clone(),
+            next_root: AtomicUsize::new(0),
+            forwarder: match config.resolver.mode {
+                ResolutionMode::Recursive => None,
+                ResolutionMode::Forward => Some(Forwarder::new(
+                    &config.resolver.upstreams,
+                    config.resolver.strategy,
+                )?),
+            },
+            conditional,
+            permits: Arc::new(Semaphore::new(config.resolver.max_concurrent_queries)),
+            cache: SyncMutex::new(Cache::new(config.cache.size)),
+            groups: RwLock::new(ClientGroups::new(BlockingPolicy::new(
+                config.blocking.mode,
+                config.blocking.ttl,
+            ))),
+            pauses: SyncMutex::new(Pauses::default()),
+            query_log: RwLock::new(None),
+            stats: SyncMutex::new(Statistics::new(
+                STATS_BUCKET,
+                stats_retention,
+                anonymizer.clone(),
+            )),
+            anonymizer,
+            metrics: Metrics::default(),
+        })
+    }
+
+    /// Replaces the policies of every group, queries being resolved keep using the previous ones
+    pub fn set_groups(&self, groups: ClientGroups) {
+        *self.groups.write().unwrap_or_else(|e| e.into_inner()) = groups;
+    }
+
+    pub fn groups(&self) -> RwLockReadGuard<'_, ClientGroups> {
+        self.groups.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Pauses blocking for the clients of `group`, or every client if `None`, for `duration`
+    /// or until resumed
+    pub fn pause_blocking(&self, group: Option<&str>, duration: Option<Duration>) -> Result<()> {
+        if let Some(group) = group.filter(|group| !self.groups().contains(group)) {
+            return Err(Error::UnknownGroup(group.to_owned()));
+        }
+        self.pauses().pause(group, duration);
+
+        Ok(())
+    }
+
+    /// Resumes blocking for the clients of `group`, or every client if `None`
+    pub fn resume_blocking(&self, group: Option<&str>) -> Result<()> {
+        if let Some(group) = group.filter(|group| !self.groups().contains(group)) {
+            return Err(Error::UnknownGroup(group.to_owned()));
+        }
+        self.pauses().resume(group);
+
+        Ok(())
+    }
+
+    pub fn pause_status(&self) -> Vec<PauseStatus> {
+        self.pauses().status()
+    }
+
+    fn pauses(&self) -> MutexGuard<'_, Pauses> {
+        self.pauses.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// How clients are recorded, which a query log must share so that hashes match the
+    /// statistics
+    pub fn anonymizer(&self) -> Anonymizer {
+        self.anonymizer.clone()
+    }
+
+    /// Starts recording every query handled to `log`
+    pub fn set_query_log(&self, log: QueryLog) {
+        *self.query_log.write().unwrap_or_else(|e| e.into_inner()) = Some(log);
+    }
+
+    /// The last `limit` queries recorded to the query log, the most recent first
+    pub fn recent_queries(&self, limit: usize) -> Vec<serde_json::Value> {
+        match self
+            .query_log
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+        {
+            Some(log) => log.recent(limit),
+            None => Vec::new(),
         }
     }
 
-    pub fn lookup(
+    /// Called once a query has been handled, with what became of it
+    fn record_query(&self, entry: QueryEntry) {
+        self.metrics.query(entry.qtype, entry.rcode, entry.blocked);
+        self.stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(&entry);
+
+        if let Some(log) = self
+            .query_log
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+        {
+            log.record(entry);
+        }
+    }
+
+    /// The statistics of the queries handled over the last `window`
+    pub fn query_stats(&self, window: Duration, top: usize) -> Summary {
+        self.stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .summary(window, top)
+    }
+
+    /// Every entry of the cache about `qname`
+    pub fn inspect_cache(&self, qname: &str) -> Vec<CachedEntry> {
+        self.cache().inspect(qname)
+    }
+
+    pub fn flush_cache(&self) {
+        self.cache().clear();
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache().stats()
+    }
+
+    /// Every metric, in the Prometheus text format
+    pub fn metrics(&self) -> String {
+        self.metrics.render(self.cache_stats())
+    }
+
+    /// The cache is never left in an inconsistent state, so it's fine to keep using it even if a
+    /// task panicked while holding it.
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub async fn lookup(
         &self,
         qname: &str,
         qtype: RecordType,
-        server: (Ipv4Addr, u16),
+        server: SocketAddr,
     ) -> Result<Packet> {
-        // Forge a query packet
-        let mut send_packet: Packet = Default::default();
-        send_packet.header.recursion_desired = true;
-        send_packet.add_question(qname, qtype)?;
+        let started = Instant::now();
+        let result = self.exchange(qname, qtype, server).await;
+        self.metrics
+            .upstream(server, started.elapsed(), result.is_ok());
 
-        // Write that packet to a buffer to send
-        let mut send_buffer = PacketBuffer::new();
-        send_packet.write(&mut send_buffer)?;
+        result
+    }
 
-        let socket = UdpSocket::bind((self.local_addr.to_owned(), self.local_port))
+    /// Sends a single query to `server` over UDP, then over TCP if the answer was truncated
+    async fn exchange(&self, qname: &str, qtype: RecordType, server: SocketAddr) -> Result<Packet> {
+        let id = random_id();
+        let query = write_query(id, qname, qtype)?;
+
+        // Upstreams of the other family are reached from any address of theirs
+        let local_addr = match (self.local_addr, server) {
+            (IpAddr::V4(_), SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            (IpAddr::V6(_), SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            (local_addr, _) => local_addr,
+        };
+        let socket = UdpSocket::bind((local_addr, self.local_port))
+            .await
             .map_err(|_| Error::UDPBindFailed)?;
+        // Once connected, the socket only receives datagrams from the server
         socket
-            .send_to(&send_buffer.bytes[0..send_buffer.pos()], server)
-            .map_err(|e| {
-                eprintln!("{e}");
-                Error::UDPSendFailed
-            })?;
+            .connect(server)
+            .await
+            .map_err(|_| Error::UDPSendFailed)?;
+        socket.send(&query).await.map_err(|e| {
+            eprintln!("{e}");
+            Error::UDPSendFailed
+        })?;
+
+        // Unresponsive servers must not hold the query forever. Anything else than the answer
+        // to the query is dropped, whoever sent it can't get it cached.
+        let receive = async {
+            loop {
+                let mut recv_bytes = vec![0; EDNS_PACKET_SIZE];
+                let len = socket
+                    .recv(&mut recv_bytes)
+                    .await
+                    .map_err(|_| Error::UDPRecvFailed)?;
+                recv_bytes.truncate(len);
+
+                match Packet::try_from(PacketBuffer::from(recv_bytes)) {
+                    Ok(packet) if is_answer_to(&packet, id, qname, qtype) => return Ok(packet),
+                    Ok(_) => eprintln!("Dropping an answer from {} to another query", server),
+                    Err(e) => self.metrics.parse_error(&e),
+                }
+            }
+        };
+        let recv_packet = timeout(UPSTREAM_TIMEOUT, receive)
+            .await
+            .map_err(|_| Error::LookupTimeout)??;
+
+        // The answer didn't fit in an UDP datagram, ask again over TCP to get all of it
+        if recv_packet.header.is_truncated {
+            return self.lookup_tcp(qname, qtype, server).await;
+        }
+
+        Ok(recv_packet)
+    }
+
+    /// Sends a query to an upstream resolver, over the transport it is reached with
+    async fn lookup_upstream(
+        &self,
+        qname: &str,
+        qtype: RecordType,
+        upstream: &Upstream,
+    ) -> Result<Packet> {
+        // Queries are written the same way whatever the transport, only UDP may need them to be
+        // sent again over TCP. The ID is 0 over TLS, where the client picks its own, and over
+        // HTTPS, where it makes answers easier to cache (see RFC8484#4.1).
+        let started = Instant::now();
+        let answer = match &upstream.transport {
+            UpstreamTransport::Udp => return self.lookup(qname, qtype, upstream.addr).await,
+            UpstreamTransport::Tls(client) => client.exchange(&write_query(0, qname, qtype)?).await,
+            UpstreamTransport::Https(client) => {
+                client.exchange(&write_query(0, qname, qtype)?).await
+            }
+        };
+        let result = answer.and_then(|answer| self.parse_answer(answer, 0, qname, qtype));
+        self.metrics
+            .upstream(upstream.addr, started.elapsed(), result.is_ok());
+
+        result
+    }
+
+    /// Sends a query over TCP, where messages are prefixed with their length on 2 bytes as
+    /// described in [RFC1035#4.2.2](https://www.rfc-editor.org/rfc/rfc1035#section-4.2.2).
+    async fn lookup_tcp(
+        &self,
+        qname: &str,
+        qtype: RecordType,
+        server: SocketAddr,
+    ) -> Result<Packet> {
+        let id = random_id();
+        let query = write_query(id, qname, qtype)?;
+        let exchange = async {
+            let mut stream = TcpStream::connect(server)
+                .await
+                .map_err(|_| Error::TCPConnectFailed)?;
+
+            write_message(&mut stream, &query).await?;
+            read_message(&mut stream).await?.ok_or(Error::TCPRecvFailed)
+        };
+
+        let recv_bytes = timeout(TCP_TIMEOUT, exchange)
+            .await
+            .map_err(|_| Error::LookupTimeout)??;
+
+        self.parse_answer(recv_bytes, id, qname, qtype)
+    }
+
+    /// Parses the answer to the query with the ID `id` for `qname`/`qtype`, failing if it is
+    /// the answer to another one
+    fn parse_answer(
+        &self,
+        bytes: Vec<u8>,
+        id: u16,
+        qname: &str,
+        qtype: RecordType,
+    ) -> Result<Packet> {
+        let packet = Packet::try_from(PacketBuffer::from(bytes))
+            .inspect_err(|e| self.metrics.parse_error(e))?;
+        if !is_answer_to(&packet, id, qname, qtype) {
+            return Err(Error::MismatchedAnswer);
+        }
+
+        Ok(packet)
+    }
+
+    /// Serves UDP queries forever. Each of them is resolved in its own task, so that a slow
+    /// lookup doesn't hold the other clients back.
+    pub async fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> Result<()> {
+        let socket = Arc::new(socket);
+
+        loop {
+            // Don't even read the next query if too many are already being resolved
+            let permit = Arc::clone(&self.permits)
+                .acquire_owned()
+                .await
+                .map_err(|_| Error::ServerShutdown)?;
+
+            let mut req_bytes = vec![0; EDNS_PACKET_SIZE];
+
+            // The `recv_from` function will write the data into the provided buffer,
+            // and return the length of the data read as well as the source address.
+            // The length is used to trim the buffer, and we need to keep track of the
+            // source in order to send our reply later on.
+            let (len, src) = match socket.recv_from(&mut req_bytes).await {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("An error occurred: {}", e);
+                    continue;
+                }
+            };
+            req_bytes.truncate(len);
+
+            let server = Arc::clone(&self);
+            let socket = Arc::clone(&socket);
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_query(&socket, req_bytes, src).await {
+                    eprintln!("An error occurred: {}", e);
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    /// Answers a single query received from `src` over UDP.
+    pub async fn handle_query(
+        &self,
+        socket: &UdpSocket,
+        req_bytes: Vec<u8>,
+        src: SocketAddr,
+    ) -> Result<()> {
+        let res_bytes = self.handle_request(req_bytes, src, Transport::Udp).await?;
 
-        let mut recv_buffer = PacketBuffer::new();
         socket
-            .recv_from(&mut recv_buffer.bytes)
-            .map_err(|_| Error::UDPRecvFailed)?;
+            .send_to(&res_bytes, src)
+            .await
+            .map_err(|_| Error::UDPSendFailed)?;
+
+        Ok(())
+    }
 
-        let recv_packet = Packet::try_from(recv_buffer)?;
+    /// Accepts TCP connections forever, each of them being served in its own task.
+    pub async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, src) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("An error occurred: {}", e);
+                    continue;
+                }
+            };
 
-        Ok(recv_packet)
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_stream(stream, src).await {
+                    eprintln!("An error occurred: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Accepts TLS connections forever, each of them being served in its own task once the
+    /// handshake is done, see [RFC7858](https://www.rfc-editor.org/rfc/rfc7858).
+    pub async fn serve_tls(
+        self: Arc<Self>,
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+    ) -> Result<()> {
+        loop {
+            let (stream, src) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("An error occurred: {}", e);
+                    continue;
+                }
+            };
+
+            let server = Arc::clone(&self);
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                // Clients which don't go through with the handshake must not hold the connection
+                let stream = match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        eprintln!("TLS handshake with {} failed: {}", src, e);
+                        return;
+                    }
+                    Err(_) => return,
+                };
+                if let Err(e) = server.handle_stream(stream, src).await {
+                    eprintln!("An error occurred: {}", e);
+                }
+            });
+        }
     }
 
-    pub fn handle_query(&self, socket: &UdpSocket) -> Result<()> {
-        // With a socket ready, we can go ahead and read a packet. This will
-        // block until one is received.
-        let mut req_buffer = PacketBuffer::new();
+    /// Serves every query sent over a stream connection, until the client closes it or stays
+    /// idle for too long. Queries and answers are framed as described in
+    /// [RFC1035#4.2.2](https://www.rfc-editor.org/rfc/rfc1035#section-4.2.2).
+    ///
+    /// Queries can be pipelined: each of them is resolved as soon as it is read, and answers are
+    /// written back as they are ready, possibly out of order (see
+    /// [RFC7766#6.2.1.1](https://www.rfc-editor.org/rfc/rfc7766#section-6.2.1.1)), up to
+    /// `STREAM_MAX_PENDING` at once.
+    pub async fn handle_stream<S>(self: Arc<Self>, stream: S, src: SocketAddr) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, writer) = tokio::io::split(stream);
+        let writer = Arc::new(Mutex::new(writer));
+        let pending = Arc::new(Semaphore::new(STREAM_MAX_PENDING));
 
-        // The `recv_from` function will write the data into the provided buffer,
-        // and return the length of the data read as well as the source address.
-        // We're not interested in the length, but we need to keep track of the
-        // source in order to send our reply later on.
-        let (_, src) = socket
-            .recv_from(&mut req_buffer.bytes)
-            .map_err(|_| Error::UDPRecvFailed)?;
+        loop {
+            // A single client can't take every slot, its next queries wait for its answers
+            let slot = Arc::clone(&pending)
+                .acquire_owned()
+                .await
+                .map_err(|_| Error::ServerShutdown)?;
+
+            // The client is done with us, or forgot about us
+            let req_bytes = match timeout(TCP_IDLE_TIMEOUT, read_message(&mut reader)).await {
+                Ok(Ok(Some(bytes))) => bytes,
+                Ok(Ok(None)) | Err(_) => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+            };
+
+            let permit = Arc::clone(&self.permits)
+                .acquire_owned()
+                .await
+                .map_err(|_| Error::ServerShutdown)?;
+
+            let server = Arc::clone(&self);
+            let writer = Arc::clone(&writer);
+            tokio::spawn(async move {
+                let result = match server.handle_request(req_bytes, src, Transport::Tcp).await {
+                    Ok(res_bytes) => write_message(&mut *writer.lock().await, &res_bytes).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    eprintln!("An error occurred: {}", e);
+                }
+                drop(permit);
+                drop(slot);
+            });
+        }
+    }
 
-        // Next, `DnsPacket::from_buffer` is used to parse the raw bytes into
-        // a `DnsPacket`.
-        let mut request = Packet::try_from(req_buffer)?;
+    /// Resolves a single raw query received from `src` over `transport`, and returns the raw
+    /// answer.
+    async fn handle_request(
+        &self,
+        req_bytes: Vec<u8>,
+        src: SocketAddr,
+        transport: Transport,
+    ) -> Result<Vec<u8>> {
+        let (time, started) = (SystemTime::now(), Instant::now());
+
+        // Next, `Packet::try_from` is used to parse the raw bytes into a `Packet`.
+        let mut request = Packet::try_from(PacketBuffer::from(req_bytes))
+            .inspect_err(|e| self.metrics.parse_error(e))?;
+
+        // Clients supporting EDNS(0) tell us how large of an UDP answer they can handle
+        let edns = match request.get_opt() {
+            Some(Record::OPT {
+                udp_payload_size,
+                version,
+                ..
+            }) => Some((*udp_payload_size, *version)),
+            _ => None,
+        };
 
         // Create and initialize the response packet
         let mut packet: Packet = Default::default();
@@ -77,36 +562,94 @@ impl Server {
         packet.header.recursion_available = true;
         packet.header.is_response = true;
 
+        // What became of the query, for the query log
+        let mut trace = LookupTrace::default();
+        let mut blocked = false;
+        let mut rule = None;
+
+        // Only EDNS version 0 exists, anything else gets a `BADVERS` (extended RCODE 16) back
+        let mut extended_rcode = 0;
+        if let Some((_, version)) = edns.filter(|(_, version)| *version > 0) {
+            eprintln!("Unsupported EDNS version {}", version);
+            extended_rcode = 1;
+        }
         // In the normal case, exactly one question is present
-        if let Some(question) = request.questions.pop() {
-            println!("Received query: {}", question);
+        else if let Some(question) = request.questions.pop() {
+            // Blocked names are answered right away, without going through the network
+            let (decision, ttl, paused) = {
+                let groups = self.groups();
+                let (group, policy) = groups.policy_for(src.ip());
+
+                if self.pauses().is_paused(group) {
+                    (Decision::Unmatched, policy.ttl, true)
+                } else {
+                    (policy.check(&question.name), policy.ttl, false)
+                }
+            };
+            if let Decision::Allowed(ref matched) = decision {
+                rule = Some(matched.to_string());
+            }
 
+            if let Decision::Blocked(matched, mode) = decision {
+                blocked = true;
+                rule = Some(matched.to_string());
+
+                mode.answer(&question, ttl, &mut packet);
+                packet.questions.push(question);
+                packet.header.question_count += 1;
+            }
             // Since all is set up and as expected, the query can be forwarded to the
             // target server. There's always the possibility that the query will
             // fail, in which case the `SERVFAIL` response code is set to indicate
             // as much to the client. If rather everything goes as planned, the
             // question and response records as copied into our response packet.
-            if let Ok(result) = self.recursive_lookup(&question.name, question.question_type) {
-                println!("Result: {}", result);
+            else if let Ok(result) = self
+                .resolve(&question.name, question.question_type, &mut trace)
+                .await
+            {
+                // Unless the name was explicitly allowed, the names it is an alias of must not
+                // be blocked either
+                let cname_decision = match decision {
+                    Decision::Allowed(_) => Decision::Unmatched,
+                    _ if paused => Decision::Unmatched,
+                    _ => {
+                        let groups = self.groups();
+                        groups.policy_for(src.ip()).1.check_cnames(&result.answers)
+                    }
+                };
+
+                if let Decision::Blocked(matched, mode) = cname_decision {
+                    blocked = true;
+                    rule = Some(matched.to_string());
+
+                    mode.answer(&question, ttl, &mut packet);
+                } else {
+                    packet.header.response_code = result.header.response_code;
+
+                    for rec in result.answers {
+                        packet.answers.push(rec);
+                        packet.header.answer_count += 1;
+                    }
+                    for rec in result.authorities {
+                        packet.authorities.push(rec);
+                        packet.header.authority_count += 1;
+                    }
+                    // The upstream `OPT` is hop-by-hop, ours gets added below
+                    for rec in result.additionals {
+                        if matches!(rec, Record::OPT { .. }) {
+                            continue;
+                        }
+                        packet.additionals.push(rec);
+                        packet.header.additional_count += 1;
+                    }
+                }
 
                 packet.questions.push(question);
                 packet.header.question_count += 1;
-                packet.header.response_code = result.header.response_code;
-
-                for rec in result.answers {
-                    packet.answers.push(rec);
-                    packet.header.answer_count += 1;
-                }
-                for rec in result.authorities {
-                    packet.authorities.push(rec);
-                    packet.header.authority_count += 1;
-                }
-                for rec in result.additionals {
-                    packet.additionals.push(rec);
-                    packet.header.additional_count += 1;
-                }
             } else {
                 packet.header.response_code = ResultCode::ServFail;
+                packet.questions.push(question);
+                packet.header.question_count += 1;
             }
         }
         // Being mindful of how unreliable input data from arbitrary senders can be, we
@@ -116,33 +659,101 @@ impl Server {
             packet.header.response_code = ResultCode::FormErr;
         }
 
-        // The only thing remaining is to encode our response and send it off!
-        let mut res_buffer = PacketBuffer::new();
-        packet.write(&mut res_buffer)?;
+        // Answer EDNS(0) queries with an `OPT` record of our own
+        if edns.is_some() {
+            packet.add_opt(EDNS_PACKET_SIZE as u16, extended_rcode, false)?;
+        }
 
-        let len = res_buffer.pos();
-        let data = res_buffer.get_range(0, len)?;
+        // The only thing remaining is to encode our response and send it off!
+        let limit = match transport {
+            Transport::Udp => edns.map_or(UDP_PACKET_SIZE, |(size, _)| {
+                (size as usize).clamp(UDP_PACKET_SIZE, EDNS_PACKET_SIZE)
+            }),
+            Transport::Tcp => MAX_PACKET_SIZE,
+        };
+        let mut res_buffer = PacketBuffer::with_limit(limit);
+        match packet.write(&mut res_buffer) {
+            Ok(()) => {}
+            // Too large for the client, send what we can with the TC bit set so it retries
+            // over TCP
+            Err(Error::PacketBufferExhausted(..)) => {
+                packet.truncate();
+                res_buffer = PacketBuffer::with_limit(limit);
+                packet.write(&mut res_buffer)?;
+            }
+            Err(e) => return Err(e),
+        }
 
-        socket
-            .send_to(data, src)
-            .map_err(|_| Error::UDPSendFailed)?;
+        if trace.depth > 0 {
+            self.metrics.recursion_depth(trace.depth);
+        }
+        // Queries without a question are not worth recording
+        if let Some(question) = packet.questions.first() {
+            self.record_query(QueryEntry {
+                time,
+                client: src,
+                name: question.name.clone(),
+                qtype: question.question_type,
+                rcode: packet.header.response_code,
+                answers: packet.answers.clone(),
+                upstream: trace.upstream,
+                cached: trace.cached,
+                blocked,
+                rule,
+                elapsed: started.elapsed(),
+            });
+        }
 
-        Ok(())
+        let len = res_buffer.pos();
+        Ok(res_buffer.get_range(0, len)?.to_vec())
     }
 
-    pub fn recursive_lookup(&self, qname: &str, qtype: RecordType) -> Result<Packet> {
-        // For now we're always starting with *a.root-servers.net*.
-        let mut ns = "198.41.0.4".parse::<Ipv4Addr>().unwrap();
+    /// Resolves `qname`, recursively or through the upstreams, telling in `trace` where the
+    /// answer came from.
+    async fn resolve(
+        &self,
+        qname: &str,
+        qtype: RecordType,
+        trace: &mut LookupTrace,
+    ) -> Result<Packet> {
+        // Answer straight from the cache if we already know about this name, or know that it
+        // doesn't exist
+        let cached = self.cache().get(qname, qtype);
+        if let Some(packet) = cached {
+            trace.cached = true;
+            return Ok(packet);
+        }
+        trace.depth = trace.depth.max(1);
+
+        // Domains with upstreams of their own come first, they may be unknown to the others
+        let forwarder = self
+            .conditional
+            .forwarder_for(qname)
+            .or(self.forwarder.as_ref().map(|forwarder| ("", forwarder)));
+        if let Some((zone, forwarder)) = forwarder {
+            return self.forward(forwarder, zone, qname, qtype, trace).await;
+        }
+
+        // Start from the closest zone we know a name server of, or one of the root servers.
+        // Servers are only trusted with the zones below their own.
+        let closest = self.cache().closest_name_server(qname);
+        let (mut zone, mut ns) = closest.unwrap_or_else(|| {
+            let next = self.next_root.fetch_add(1, Ordering::Relaxed);
+            (
+                String::new(),
+                self.root_servers[next % self.root_servers.len()],
+            )
+        });
 
         // Since it might take an arbitrary number of steps, we enter an unbounded loop.
         loop {
-            println!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
-
             // The next step is to send the query to the active server.
             let ns_copy = ns;
 
-            let server = (ns_copy, 53);
-            let response = self.lookup(qname, qtype, server)?;
+            let server = SocketAddr::from((ns_copy, 53));
+            let response = self.lookup(qname, qtype, server).await?;
+            trace.upstream = Some(server);
+            self.cache().insert_packet(qname, qtype, &zone, &response);
 
             // If there are entries in the answer section, and no errors, we are done!
             if !response.answers.is_empty() && response.header.response_code == ResultCode::NoError
@@ -159,7 +770,8 @@ impl Server {
             // Otherwise, we'll try to find a new nameserver based on NS and a corresponding A
             // record in the additional section. If this succeeds, we can switch name server
             // and retry the loop.
-            if let Some(new_ns) = response.get_resolved_ns(qname) {
+            if let Some((child, new_ns)) = response.get_resolved_ns(qname, &zone) {
+                zone = child.to_owned();
                 ns = new_ns;
 
                 continue;
@@ -167,7 +779,7 @@ impl Server {
 
             // If not, we'll have to resolve the ip of a NS record. If no NS records exist,
             // we'll go with what the last server told us.
-            let new_ns_name = match response.get_unresolved_ns(qname) {
+            let (child, new_ns_name) = match response.get_unresolved_ns(qname, &zone) {
                 Some(x) => x,
                 None => return Ok(response),
             };
@@ -175,17 +787,128 @@ impl Server {
             // Here we go down the rabbit hole by starting _another_ lookup sequence in the
             // midst of our current one. Hopefully, this will give us the IP of an appropriate
             // name server.
-            let recursive_response = self.recursive_lookup(&new_ns_name, RecordType::A)?;
+            let mut nested = LookupTrace::default();
+            let recursive_response =
+                Box::pin(self.resolve(new_ns_name, RecordType::A, &mut nested)).await;
+            trace.depth = trace.depth.max(nested.depth + 1);
+            let recursive_response = recursive_response?;
 
             // Finally, we pick a random ip from the result, and restart the loop. If no such
             // record is available, we again return the last result we got.
             if let Some(new_ns) = recursive_response.get_random_a() {
+                zone = child.to_owned();
                 ns = new_ns;
             } else {
                 return Ok(response);
             }
         }
     }
+
+    /// Asks the upstreams in turn until one of them answers. An upstream failing to resolve the
+    /// name doesn't mean the next one will, their answer is only kept if none does better.
+    /// Upstreams are trusted with the names of `zone`, the root in forward mode.
+    async fn forward(
+        &self,
+        forwarder: &Forwarder,
+        zone: &str,
+        qname: &str,
+        qtype: RecordType,
+        trace: &mut LookupTrace,
+    ) -> Result<Packet> {
+        let mut last = Err(Error::NoUpstream);
+        for (index, upstream) in forwarder.order() {
+            let started = Instant::now();
+            let response = match self.lookup_upstream(qname, qtype, upstream).await {
+                Ok(response) => response,
+                Err(e) => {
+                    eprintln!("Upstream {} failed: {:?}", upstream, e);
+                    forwarder.failure(index);
+                    last = Err(e);
+                    continue;
+                }
+            };
+            trace.upstream = Some(upstream.addr);
+
+            // An upstream which can't or won't resolve names is no better than one which doesn't
+            // answer, it must not be sidelined less or measured as the fastest
+            if matches!(
+                response.header.response_code,
+                ResultCode::ServFail | ResultCode::Refused
+            ) {
+                forwarder.failure(index);
+                last = Ok(response);
+                continue;
+            }
+            forwarder.success(index, started.elapsed());
+
+            self.cache().insert_packet(qname, qtype, zone, &response);
+            return Ok(response);
+        }
+
+        last
+    }
+}
+
+/// A random query ID, so that answers are hard to spoof
+fn random_id() -> u16 {
+    let mut id = [0; 2];
+    // The system random source only fails on platforms we don't run on
+    let _ = SystemRandom::new().fill(&mut id);
+    u16::from_be_bytes(id)
+}
+
+/// Writes a query for `qname` with the ID `id`, as sent to upstream servers
+fn write_query(id: u16, qname: &str, qtype: RecordType) -> Result<Vec<u8>> {
+    let mut packet = Packet::default();
+    packet.header.id = id;
+    packet.header.recursion_desired = true;
+    packet.add_question(qname, qtype)?;
+    // Advertise EDNS(0) so that upstreams can answer with more than 512 bytes
+    packet.add_opt(EDNS_PACKET_SIZE as u16, 0, false)?;
+
+    let mut buffer = PacketBuffer::new();
+    packet.write(&mut buffer)?;
+    let len = buffer.pos();
+    Ok(buffer.get_range(0, len)?.to_vec())
+}
+
+/// Tells whether `packet` answers the query with the ID `id` for `qname`/`qtype`
+fn is_answer_to(packet: &Packet, id: u16, qname: &str, qtype: RecordType) -> bool {
+    packet.header.is_response
+        && packet.header.id == id
+        && matches!(packet.questions.as_slice(), [question]
+            if question.name.eq_ignore_ascii_case(qname) && question.question_type == qtype)
+}
+
+/// Reads a message prefixed by its length on 2 bytes, `None` if the stream was closed before
+/// a new one started.
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(_) => return Err(Error::TCPRecvFailed),
+    }
+
+    let mut bytes = vec![0; u16::from_be_bytes(len) as usize];
+    reader
+        .read_exact(&mut bytes)
+        .await
+        .map_err(|_| Error::TCPRecvFailed)?;
+
+    Ok(Some(bytes))
+}
+
+/// Writes a message prefixed by its length on 2 bytes.
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    let mut message = Vec::with_capacity(bytes.len() + 2);
+    message.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    message.extend_from_slice(bytes);
+
+    writer
+        .write_all(&message)
+        .await
+        .map_err(|_| Error::TCPSendFailed)
 }
 
 impl fmt::Display for Server {
@@ -0,0 +1,211 @@
+//! Statistics about the queries handled over the last hours: how many were blocked, which
+//! names and clients are the most frequent, and how types and response codes are distributed.
+//!
+//! Queries are counted in buckets of a fixed width, the counters of a window being the sum of
+//! the buckets it spans. Buckets older than the largest window are dropped as time goes.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Formatter};
+use std::hash::Hash;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::querylog::{Anonymizer, QueryEntry};
+use crate::record::RecordType;
+use crate::result::ResultCode;
+
+/// What happened during a bucket of time
+#[derive(Default)]
+struct Counters {
+    queries: u64,
+    blocked: u64,
+    domains: HashMap<String, u64>,
+    blocked_domains: HashMap<String, u64>,
+    /// Keyed by what the anonymizer makes of the clients
+    clients: HashMap<String, u64>,
+    types: HashMap<RecordType, u64>,
+    rcodes: HashMap<ResultCode, u64>,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.queries += other.queries;
+        self.blocked += other.blocked;
+        merge(&mut self.domains, &other.domains);
+        merge(&mut self.blocked_domains, &other.blocked_domains);
+        merge(&mut self.clients, &other.clients);
+        merge(&mut self.types, &other.types);
+        merge(&mut self.rcodes, &other.rcodes);
+    }
+}
+
+/// The figures of a window of time
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub window: Duration,
+    pub queries: u64,
+    pub blocked: u64,
+    /// The most queried names, most frequent first
+    pub top_domains: Vec<(String, u64)>,
+    /// The most blocked names, most frequent first
+    pub top_blocked: Vec<(String, u64)>,
+    /// The clients sending the most queries, most frequent first, hashed or left out as the
+    /// privacy mode of the query log says
+    pub top_clients: Vec<(String, u64)>,
+    /// Every type queried, most frequent first
+    pub types: Vec<(RecordType, u64)>,
+    /// Every response code answered, most frequent first
+    pub rcodes: Vec<(ResultCode, u64)>,
+}
+
+impl Summary {
+    /// Share of the queries which were blocked, in percent
+    pub fn blocked_percent(&self) -> f64 {
+        if self.queries == 0 {
+            return 0.0;
+        }
+        self.blocked as f64 * 100.0 / self.queries as f64
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Last {}s: {} queries, {} blocked ({:.1}%)",
+            self.window.as_secs(),
+            self.queries,
+            self.blocked,
+            self.blocked_percent()
+        )?;
+        write_top(f, "Top domains", &self.top_domains)?;
+        write_top(f, "Top blocked", &self.top_blocked)?;
+        write_top(f, "Top clients", &self.top_clients)?;
+        write_top(f, "Types", &self.types)?;
+        write_top(f, "Response codes", &self.rcodes)
+    }
+}
+
+/// Counters of the queries handled, kept for `retention`
+pub struct Statistics {
+    /// Buckets from the oldest to the most recent, along with the index of the bucket, in
+    /// widths since the epoch
+    buckets: VecDeque<(u64, Counters)>,
+    width: Duration,
+    retention: Duration,
+    /// Hides the clients the same way as the query log
+    anonymizer: Anonymizer,
+}
+
+impl Statistics {
+    pub fn new(width: Duration, retention: Duration, anonymizer: Anonymizer) -> Self {
+        Self {
+            buckets: VecDeque::new(),
+            width,
+            retention,
+            anonymizer,
+        }
+    }
+
+    /// Counts a query
+    pub fn record(&mut self, entry: &QueryEntry) {
+        let index = self.index(entry.time);
+        self.expire(index);
+
+        // Entries come roughly in order, those late for their bucket go to the last one
+        let counters = match self.buckets.back_mut() {
+            Some((last, counters)) if *last >= index => counters,
+            _ => {
+                self.buckets.push_back((index, Counters::default()));
+                &mut self.buckets.back_mut().unwrap().1
+            }
+        };
+
+        counters.queries += 1;
+        *counters
+            .domains
+            .entry(entry.name.to_lowercase())
+            .or_default() += 1;
+        if entry.blocked {
+            counters.blocked += 1;
+            *counters
+                .blocked_domains
+                .entry(entry.name.to_lowercase())
+                .or_default() += 1;
+        }
+        if let Some(client) = self.anonymizer.client(entry.client.ip()) {
+            *counters.clients.entry(client).or_default() += 1;
+        }
+        *counters.types.entry(entry.qtype).or_default() += 1;
+        *counters.rcodes.entry(entry.rcode).or_default() += 1;
+    }
+
+    /// The figures of the last `window`, keeping the `top` most frequent names and clients
+    pub fn summary(&mut self, window: Duration, top: usize) -> Summary {
+        let now = self.index(SystemTime::now());
+        self.expire(now);
+
+        let buckets = (window.as_secs() / self.width.as_secs().max(1)).max(1);
+        let since = (now + 1).saturating_sub(buckets);
+
+        let mut total = Counters::default();
+        for (_, counters) in self.buckets.iter().filter(|(index, _)| *index >= since) {
+            total.add(counters);
+        }
+
+        Summary {
+            window,
+            queries: total.queries,
+            blocked: total.blocked,
+            top_domains: sorted(total.domains, top),
+            top_blocked: sorted(total.blocked_domains, top),
+            top_clients: sorted(total.clients, top),
+            types: sorted(total.types, usize::MAX),
+            rcodes: sorted(total.rcodes, usize::MAX),
+        }
+    }
+
+    /// The bucket `time` falls in
+    fn index(&self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        secs / self.width.as_secs().max(1)
+    }
+
+    /// Drops the buckets which are too old to be part of any window at `now`
+    fn expire(&mut self, now: u64) {
+        let kept = self.retention.as_secs() / self.width.as_secs().max(1);
+        let oldest = (now + 1).saturating_sub(kept);
+        while matches!(self.buckets.front(), Some((index, _)) if *index < oldest) {
+            self.buckets.pop_front();
+        }
+    }
+}
+
+fn merge<K: Eq + Hash + Clone>(into: &mut HashMap<K, u64>, from: &HashMap<K, u64>) {
+    for (key, count) in from {
+        *into.entry(key.clone()).or_default() += count;
+    }
+}
+
+/// The `top` most frequent keys, most frequent first
+fn sorted<K: Ord>(counts: HashMap<K, u64>, top: usize) -> Vec<(K, u64)> {
+    let mut counts: Vec<(K, u64)> = counts.into_iter().collect();
+    // Ties are broken by key, so that the order doesn't change from one call to the next
+    counts.sort_unstable_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    counts.truncate(top);
+    counts
+}
+
+fn write_top<K: fmt::Display>(
+    f: &mut Formatter<'_>,
+    title: &str,
+    counts: &[(K, u64)],
+) -> fmt::Result {
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|(key, count)| format!("{} ({})", key, count))
+        .collect();
+    writeln!(f, "{}: {}", title, counts.join(", "))
+}